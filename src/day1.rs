@@ -1,6 +1,6 @@
 use std::{collections::HashMap, error::Error, fs};
 
-pub fn solve() {
+pub fn solve(_args: &[String]) {
     let (mut left, mut right) = read_input("inputs/day1.txt").unwrap();
     println!("Part 1: {}", distance(&mut left, &mut right));
     println!("Part 2: {}", similarity(&mut left, &mut right));
@@ -1,11 +1,89 @@
-use std::{error::Error, fs};
+use std::{error::Error, fmt, fs};
 
-pub fn solve() {
-    let reports = read_input("inputs/day2.txt").unwrap();
+pub fn solve(args: &[String]) {
+    let reports = match read_input("inputs/day2.txt") {
+        Ok(reports) => reports,
+        Err(err) => {
+            eprintln!("Invalid input: {err}");
+            return;
+        }
+    };
+    if args.iter().any(|arg| arg == "--explain") {
+        explain(&reports);
+    }
     println!("Part 1: {}", safe_reports(&reports));
     println!("Part 2: {}", safe_reports_2(&reports));
 }
 
+fn explain(reports: &[Vec<i32>]) {
+    for (i, report) in reports.iter().enumerate() {
+        let levels = report
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let diagnosis = match diagnose(report) {
+            Diagnosis::Safe => "safe".to_string(),
+            Diagnosis::Dampened { violation, removed } => format!(
+                "{violation}; safe after removing level {} ({})",
+                removed + 1,
+                report[removed]
+            ),
+            Diagnosis::Unsafe(violation) => format!("{violation}; unsafe"),
+        };
+        println!("Report {}: {levels} -> {diagnosis}", i + 1);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reason {
+    DirectionChange,
+    ZeroStep,
+    StepTooLarge,
+}
+
+/// The first pair of adjacent levels that makes a report unsafe. `index` is
+/// the position of the second level of the pair; it's displayed 1-based,
+/// like report numbers.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Violation {
+    index: usize,
+    reason: Reason,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            Reason::DirectionChange => "direction change",
+            Reason::ZeroStep => "zero step",
+            Reason::StepTooLarge => "step too large",
+        };
+        write!(f, "{reason} at level {}", self.index + 1)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Diagnosis {
+    Safe,
+    /// Unsafe as is, but safe once the level at `removed` is dropped.
+    Dampened {
+        violation: Violation,
+        removed: usize,
+    },
+    Unsafe(Violation),
+}
+
+fn diagnose(report: &[i32]) -> Diagnosis {
+    let violation = match check_report(report) {
+        Ok(()) => return Diagnosis::Safe,
+        Err(violation) => violation,
+    };
+    match level_to_remove(report) {
+        Some(removed) => Diagnosis::Dampened { violation, removed },
+        None => Diagnosis::Unsafe(violation),
+    }
+}
+
 fn safe_reports(reports: &[Vec<i32>]) -> usize {
     reports.iter().filter(|r| is_report_safe(r)).count()
 }
@@ -18,20 +96,30 @@ fn safe_reports_2(reports: &[Vec<i32>]) -> usize {
 }
 
 fn is_report_safe(report: &[i32]) -> bool {
-    if report.len() == 1 {
-        return true;
+    check_report(report).is_ok()
+}
+
+/// Reports with fewer than two levels have no steps to violate, so they are
+/// always safe.
+fn check_report(report: &[i32]) -> Result<(), Violation> {
+    if report.len() < 2 {
+        return Ok(());
     }
     let dir = (report[1] - report[0]).signum();
     for i in 1..report.len() {
         let diff = report[i] - report[i - 1];
-        if diff.signum() != dir {
-            return false;
-        }
-        if !is_valid_diff(diff) {
-            return false;
-        }
+        let reason = if diff == 0 {
+            Reason::ZeroStep
+        } else if diff.signum() != dir {
+            Reason::DirectionChange
+        } else if !is_valid_diff(diff) {
+            Reason::StepTooLarge
+        } else {
+            continue;
+        };
+        return Err(Violation { index: i, reason });
     }
-    true
+    Ok(())
 }
 
 fn is_valid_diff(diff: i32) -> bool {
@@ -39,36 +127,68 @@ fn is_valid_diff(diff: i32) -> bool {
 }
 
 fn is_safe_with_max_one_bad_level(report: &[i32]) -> bool {
-    if report.len() <= 2 || is_report_safe(report) {
-        return true;
-    }
+    report.len() <= 2 || is_report_safe(report) || level_to_remove(report).is_some()
+}
+
+/// Finds a level whose removal makes the report safe, preferring later levels.
+fn level_to_remove(report: &[i32]) -> Option<usize> {
     let mut report = report.to_vec();
     // Try removing one item at a time, starting from the end.
     let mut removed = report.pop().unwrap();
     for i in (0..report.len()).rev() {
+        // The level currently held in `removed` was originally at `i + 1`.
         if is_report_safe(&report) {
-            return true;
+            return Some(i + 1);
         }
         std::mem::swap(&mut removed, &mut report[i]);
     }
-    is_report_safe(&report)
+    is_report_safe(&report).then_some(0)
 }
 
+/// Errors in the puzzle input. Line numbers are 1-based.
+#[derive(Debug, PartialEq)]
+enum InputError {
+    EmptyLine(usize),
+    InvalidLevel { line: usize, token: String },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::EmptyLine(line) => write!(f, "line {line}: empty report"),
+            InputError::InvalidLevel { line, token } => {
+                write!(f, "line {line}: invalid level {token:?}")
+            }
+        }
+    }
+}
+
+impl Error for InputError {}
+
 fn read_input(input_path: &str) -> Result<Vec<Vec<i32>>, Box<dyn Error>> {
     let buf = fs::read_to_string(input_path)?;
-    let reports = buf
-        .lines()
-        .map(|line| {
-            line.split(' ')
-                .map(|v| {
-                    v.parse::<i32>()
-                        .unwrap_or_else(|_| panic!("invalid value: {}", v))
-                })
-                .collect::<Vec<_>>()
-        })
-        .collect();
+    Ok(parse_reports(&buf)?)
+}
+
+fn parse_reports(text: &str) -> Result<Vec<Vec<i32>>, InputError> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| parse_report(i + 1, line))
+        .collect()
+}
 
-    Ok(reports)
+fn parse_report(line_no: usize, line: &str) -> Result<Vec<i32>, InputError> {
+    if line.trim().is_empty() {
+        return Err(InputError::EmptyLine(line_no));
+    }
+    line.split_whitespace()
+        .map(|token| {
+            token.parse::<i32>().map_err(|_| InputError::InvalidLevel {
+                line: line_no,
+                token: token.to_string(),
+            })
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -120,4 +240,96 @@ mod test {
 
         assert_eq!(safe_reports_2(&reports), 6);
     }
+
+    #[test]
+    fn test_check_report() {
+        assert_eq!(check_report(&[7, 6, 4, 2, 1]), Ok(()));
+        let violation = |index, reason| Err(Violation { index, reason });
+        assert_eq!(
+            check_report(&[1, 2, 7, 8, 9]),
+            violation(2, Reason::StepTooLarge)
+        );
+        assert_eq!(
+            check_report(&[1, 3, 2, 4, 5]),
+            violation(2, Reason::DirectionChange)
+        );
+        assert_eq!(
+            check_report(&[8, 6, 4, 4, 1]),
+            violation(3, Reason::ZeroStep)
+        );
+        assert_eq!(check_report(&[3, 3, 4]), violation(1, Reason::ZeroStep));
+        let zero_step = Violation {
+            index: 1,
+            reason: Reason::ZeroStep,
+        };
+        assert_eq!(zero_step.to_string(), "zero step at level 2");
+    }
+
+    #[test]
+    fn test_diagnose() {
+        assert_eq!(diagnose(&[7, 6, 4, 2, 1]), Diagnosis::Safe);
+        assert_eq!(
+            diagnose(&[1, 3, 2, 4, 5]),
+            Diagnosis::Dampened {
+                violation: Violation {
+                    index: 2,
+                    reason: Reason::DirectionChange
+                },
+                removed: 2,
+            }
+        );
+        assert_eq!(
+            diagnose(&[10, 3, 6, 7, 9]),
+            Diagnosis::Dampened {
+                violation: Violation {
+                    index: 1,
+                    reason: Reason::StepTooLarge
+                },
+                removed: 0,
+            }
+        );
+        assert_eq!(
+            diagnose(&[9, 7, 6, 2, 1]),
+            Diagnosis::Unsafe(Violation {
+                index: 3,
+                reason: Reason::StepTooLarge
+            })
+        );
+    }
+
+    #[test]
+    fn test_degenerate_reports() {
+        assert!(is_report_safe(&[]));
+        assert!(is_report_safe(&[5]));
+        assert!(is_safe_with_max_one_bad_level(&[]));
+        assert!(is_safe_with_max_one_bad_level(&[5]));
+        assert_eq!(diagnose(&[]), Diagnosis::Safe);
+        assert_eq!(diagnose(&[5]), Diagnosis::Safe);
+    }
+
+    #[test]
+    fn test_parse_reports() {
+        assert_eq!(
+            parse_reports("7 6 4\n1\n"),
+            Ok(vec![vec![7, 6, 4], vec![1]])
+        );
+        assert_eq!(parse_reports(""), Ok(vec![]));
+        assert_eq!(parse_reports("7 6 4\n\n1 2"), Err(InputError::EmptyLine(2)));
+        assert_eq!(parse_reports("1 2\n   "), Err(InputError::EmptyLine(2)));
+        assert_eq!(
+            parse_reports("1 2\n3 x 4"),
+            Err(InputError::InvalidLevel {
+                line: 2,
+                token: "x".to_string()
+            })
+        );
+        assert_eq!(
+            InputError::InvalidLevel {
+                line: 2,
+                token: "x".to_string()
+            }
+            .to_string(),
+            "line 2: invalid level \"x\""
+        );
+    }
 }
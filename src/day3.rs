@@ -1,197 +1,1084 @@
-use std::{collections::HashMap, iter::Peekable, str::Chars};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+};
 
-const METHODS: [&str; 3] = ["mul(", "do(", "don't("];
+use crate::regex::{self, Captures, Regex};
 
-pub fn solve() {
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn solve(args: &[String]) {
+    let operands = if args.iter().any(|arg| arg == "--lenient") {
+        Operands::Lenient
+    } else {
+        Operands::Strict
+    };
+    if args.iter().any(|arg| arg == "--explain") {
+        let text = std::fs::read_to_string("inputs/day3.txt").unwrap();
+        let style = if args.iter().any(|arg| arg == "--plain") {
+            Style::Plain
+        } else {
+            Style::Ansi
+        };
+        explain(&text, &part2(operands), style);
+    }
+    if args.iter().any(|arg| arg == "--stream") {
+        // For inputs too large to read into memory at once. The regex engine
+        // needs the whole text, so this goes through the `Scanner` instead.
+        for (part, instructions) in [(1, part1(operands)), (2, part2(operands))] {
+            let total = File::open("inputs/day3.txt")
+                .and_then(|mut f| scan(&instructions, &mut f, CHUNK_SIZE));
+            match total {
+                Ok(total) => println!("Part {part}: {total}"),
+                Err(err) => eprintln!("Invalid input: {err}"),
+            }
+        }
+        return;
+    }
     let text = std::fs::read_to_string("inputs/day3.txt").unwrap();
-    println!("Part 1: {}", multiply(&text));
-    println!("Part 2: {}", do_or_dont_multiply(&text));
+    match multiply(&text, operands) {
+        Some(total) => println!("Part 1: {total}"),
+        None => eprintln!("Invalid input: total does not fit in a u64"),
+    }
+    match do_or_dont_multiply(&text, operands) {
+        Some(total) => println!("Part 2: {total}"),
+        None => eprintln!("Invalid input: total does not fit in a u64"),
+    }
 }
 
-fn multiply(text: &str) -> u32 {
-    let mut it = text.chars().peekable();
-    let mut ans = 0;
-    while let Some(ch) = it.next() {
-        if ch != 'm' {
-            continue;
-        }
-        if !consume_if(&mut it, 'u') || !consume_if(&mut it, 'l') || !consume_if(&mut it, '(') {
-            continue;
+fn part1(operands: Operands) -> InstructionSet {
+    InstructionSet::default().with(MUL).operands(operands)
+}
+
+fn part2(operands: Operands) -> InstructionSet {
+    part1(operands).with(DO).with(DONT)
+}
+
+/// Returns `None` if the total overflows.
+fn multiply(text: &str, operands: Operands) -> Option<u64> {
+    evaluate(&part1(operands).tokenize(text))
+}
+
+/// Returns `None` if the total overflows.
+fn do_or_dont_multiply(text: &str, operands: Operands) -> Option<u64> {
+    evaluate(&part2(operands).tokenize(text))
+}
+
+/// Describes an instruction of the form `name(arg1,...,argN)`, where every
+/// argument is a non-negative integer.
+#[derive(Debug)]
+struct InstrSpec {
+    name: &'static str,
+    arity: usize,
+    exec: fn(&mut Interpreter, &[u32]),
+}
+
+const MUL: InstrSpec = InstrSpec {
+    name: "mul",
+    arity: 2,
+    exec: |interpreter, args| interpreter.accumulate(args[0] as u64 * args[1] as u64),
+};
+
+const DO: InstrSpec = InstrSpec {
+    name: "do",
+    arity: 0,
+    exec: |interpreter, _| interpreter.enabled = true,
+};
+
+const DONT: InstrSpec = InstrSpec {
+    name: "don't",
+    arity: 0,
+    exec: |interpreter, _| interpreter.enabled = false,
+};
+
+/// How instruction arguments are validated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Operands {
+    /// 1 to 3 digits, as required by the puzzle.
+    #[default]
+    Strict,
+    /// Any number of digits, as long as the value fits in a `u32`.
+    Lenient,
+}
+
+impl Operands {
+    fn max_digits(self) -> usize {
+        match self {
+            Operands::Strict => 3,
+            Operands::Lenient => usize::MAX,
         }
-        ans += consume_multiply(&mut it);
     }
-    ans
 }
 
-fn do_or_dont_multiply(text: &str) -> u32 {
-    let mut it = text.chars().peekable();
+/// The instructions recognised by the scanner. Several specs may share a
+/// name as long as their arities differ.
+#[derive(Default)]
+struct InstructionSet {
+    specs: Vec<InstrSpec>,
+    operands: Operands,
+}
+
+impl InstructionSet {
+    fn with(mut self, spec: InstrSpec) -> Self {
+        self.specs.push(spec);
+        self
+    }
+
+    fn operands(mut self, operands: Operands) -> Self {
+        self.operands = operands;
+        self
+    }
+
+    fn build_automaton(&self) -> AhoCorasick<'static> {
+        let mut names = self.specs.iter().map(|spec| spec.name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        AhoCorasick::build(&names)
+    }
+
+    fn lookup(&self, name: &str, arity: usize) -> Option<&InstrSpec> {
+        self.specs
+            .iter()
+            .find(|spec| spec.name == name && spec.arity == arity)
+    }
+
+    /// A pattern matching any of the instructions. Each instruction gets a
+    /// group, followed by one group per argument.
+    fn build_regex(&self) -> Regex {
+        let operand = match self.operands {
+            Operands::Strict => r"(\d{1,3})",
+            Operands::Lenient => r"(\d+)",
+        };
+        let pattern = self
+            .specs
+            .iter()
+            .map(|spec| {
+                let args = vec![operand; spec.arity].join(",");
+                format!(r"({}\({}\))", regex::escape(spec.name), args)
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        Regex::new(&pattern).unwrap()
+    }
+
+    /// Extracts the well-formed instructions from corrupted memory, in order.
+    /// Gives the same results as feeding all of `text` to a `Scanner`.
+    fn tokenize(&self, text: &str) -> Vec<Token<'_>> {
+        if self.specs.is_empty() {
+            return vec![];
+        }
+        let regex = self.build_regex();
+        regex
+            .find_iter(text.as_bytes())
+            .filter_map(|captures| self.to_token(text, &captures))
+            .collect()
+    }
+
+    fn to_token(&self, text: &str, captures: &Captures) -> Option<Token<'_>> {
+        let mut group = 1;
+        for spec in &self.specs {
+            if captures.get(group).is_some() {
+                // Only fails if a lenient operand doesn't fit in a `u32`.
+                let args = (group + 1..=group + spec.arity)
+                    .map(|i| text[captures.get(i).unwrap()].parse().ok())
+                    .collect::<Option<Vec<u32>>>()?;
+                return Some(Token {
+                    instr: Instr { spec, args },
+                    span: captures.span(),
+                });
+            }
+            group += spec.arity + 1;
+        }
+        None
+    }
+}
 
-    let trie = Trie::build(&METHODS);
-    let mut enabled = true;
-    let mut ans = 0;
-    while it.peek().is_some() {
-        match trie.find(&mut it) {
-            Some("do(") => enabled = true,
-            Some("don't(") => enabled = false,
-            Some("mul(") if enabled => ans += consume_multiply(&mut it),
-            _ => {}
+/// Runs the instructions read from `reader`, `chunk_size` bytes at a time, so
+/// that memory use doesn't depend on the size of the input.
+fn scan<R: Read>(
+    instructions: &InstructionSet,
+    reader: &mut R,
+    chunk_size: usize,
+) -> io::Result<u64> {
+    let mut scanner = Scanner::new(instructions);
+    let mut interpreter = Interpreter::new();
+    let mut buf = vec![0; chunk_size];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
         };
+        for token in scanner.feed(&buf[..n]) {
+            interpreter.exec(&token.instr);
+        }
     }
-    ans
+    interpreter
+        .total
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "total does not fit in a u64"))
 }
 
-struct Trie<'a> {
-    values: HashMap<char, Trie<'a>>,
-    matches: Option<&'a str>,
+/// Incremental tokenizer. Input can be fed in arbitrary pieces; an
+/// instruction split across pieces is carried over until it completes.
+///
+/// Assumes instruction names contain no digits, commas or parentheses, so at
+/// most one argument list is being parsed at any time.
+struct Scanner<'a> {
+    instructions: &'a InstructionSet,
+    automaton: AhoCorasick<'static>,
+    state: usize,
+    /// Offset of the next byte to be fed.
+    offset: usize,
+    /// End of the last accepted instruction.
+    last_end: usize,
+    pending: Option<PendingArgs>,
 }
 
-impl Trie<'_> {
-    fn new() -> Self {
+impl<'a> Scanner<'a> {
+    fn new(instructions: &'a InstructionSet) -> Self {
         Self {
-            values: HashMap::new(),
-            matches: None,
+            instructions,
+            automaton: instructions.build_automaton(),
+            state: AhoCorasick::ROOT,
+            offset: 0,
+            last_end: 0,
+            pending: None,
         }
     }
 
-    fn build<'a>(words: &[&'a str]) -> Trie<'a> {
-        let mut root = Trie::new();
-        for word in words {
-            let mut node = &mut root;
-            for c in word.chars() {
-                node = node.values.entry(c).or_insert_with(Trie::new);
+    /// Returns the instructions completed by `chunk`. Names matched inside an
+    /// instruction that was already accepted are ignored, so `xmul(2,3)`
+    /// yields only `xmul` when both it and `mul` are registered.
+    fn feed(&mut self, chunk: &[u8]) -> Vec<Token<'a>> {
+        let mut tokens = vec![];
+        for &b in chunk {
+            let end = self.offset + 1;
+            self.offset = end;
+
+            if let Some(mut pending) = self.pending.take() {
+                match pending.push(b, self.instructions.operands) {
+                    ArgStatus::Incomplete => self.pending = Some(pending),
+                    ArgStatus::Complete => tokens.extend(self.accept(pending, end)),
+                    ArgStatus::Invalid => {}
+                }
+            }
+
+            self.state = self.automaton.step(self.state, b);
+            let candidates = self
+                .automaton
+                .outputs(self.state)
+                .map(|name| (name, end - name.len()))
+                .filter(|&(_, start)| start >= self.last_end)
+                .collect::<Vec<_>>();
+            if !candidates.is_empty() {
+                self.pending = Some(PendingArgs::new(candidates));
             }
-            node.matches = Some(word);
         }
-        root
+        tokens
     }
 
-    fn find(&self, it: &mut Peekable<Chars>) -> Option<&str> {
-        let mut node = match it.next() {
-            Some(ch) => match self.values.get(&ch) {
-                Some(node) => node,
-                None => return None,
+    fn accept(&mut self, pending: PendingArgs, end: usize) -> Option<Token<'a>> {
+        let (spec, start) = pending.candidates.into_iter().find_map(|(name, start)| {
+            let spec = self.instructions.lookup(name, pending.args.len())?;
+            Some((spec, start))
+        })?;
+        self.last_end = end;
+        Some(Token {
+            instr: Instr {
+                spec,
+                args: pending.args,
             },
-            None => return None,
-        };
-        let mut candidate = None;
-        if node.matches.is_some() {
-            candidate = node.matches;
-        }
-        while let Some(ch) = it.peek() {
-            node = match node.values.get(ch) {
-                Some(node) => {
-                    it.next();
-                    node
+            span: start..end,
+        })
+    }
+}
+
+/// The argument list following one or more matched names, parsed a byte at a
+/// time.
+struct PendingArgs {
+    /// The names ending right before the argument list, with their starts.
+    candidates: Vec<(&'static str, usize)>,
+    args: Vec<u32>,
+    expect: Expect,
+    /// Number of digits of the argument being parsed.
+    digits: usize,
+}
+
+enum Expect {
+    Open,
+    FirstArgOrClose,
+    Arg,
+    /// In the middle of a number.
+    Digit(u32),
+}
+
+enum ArgStatus {
+    Incomplete,
+    Complete,
+    Invalid,
+}
+
+impl PendingArgs {
+    fn new(candidates: Vec<(&'static str, usize)>) -> Self {
+        Self {
+            candidates,
+            args: vec![],
+            expect: Expect::Open,
+            digits: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8, operands: Operands) -> ArgStatus {
+        self.expect = match (&self.expect, b) {
+            (Expect::Open, b'(') => Expect::FirstArgOrClose,
+            (Expect::FirstArgOrClose, b')') => return ArgStatus::Complete,
+            (Expect::FirstArgOrClose | Expect::Arg, b'0'..=b'9') => {
+                self.digits = 1;
+                Expect::Digit((b - b'0') as u32)
+            }
+            (Expect::Digit(num), b'0'..=b'9') => {
+                self.digits += 1;
+                if self.digits > operands.max_digits() {
+                    return ArgStatus::Invalid;
                 }
-                None => return candidate,
-            };
-            if node.matches.is_some() {
-                candidate = node.matches;
+                match num
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add((b - b'0') as u32))
+                {
+                    Some(num) => Expect::Digit(num),
+                    None => return ArgStatus::Invalid,
+                }
+            }
+            (Expect::Digit(num), b',') => {
+                self.args.push(*num);
+                Expect::Arg
             }
+            (Expect::Digit(num), b')') => {
+                self.args.push(*num);
+                return ArgStatus::Complete;
+            }
+            _ => return ArgStatus::Invalid,
+        };
+        ArgStatus::Incomplete
+    }
+}
+
+#[derive(Debug)]
+struct Instr<'a> {
+    spec: &'a InstrSpec,
+    args: Vec<u32>,
+}
+
+/// An instruction along with the byte range it occupies in the input.
+#[derive(Debug)]
+struct Token<'a> {
+    instr: Instr<'a>,
+    span: Range<usize>,
+}
+
+/// Returns `None` if the total overflows.
+fn evaluate(tokens: &[Token]) -> Option<u64> {
+    let mut interpreter = Interpreter::new();
+    for token in tokens {
+        interpreter.exec(&token.instr);
+    }
+    interpreter.total
+}
+
+struct Interpreter {
+    enabled: bool,
+    /// `None` once the total has overflowed.
+    total: Option<u64>,
+    /// Number of values added to the total.
+    counted: usize,
+    /// Number of values ignored because of a `don't()`.
+    skipped: usize,
+}
+
+impl Interpreter {
+    fn new() -> Self {
+        Self {
+            enabled: true,
+            total: Some(0),
+            counted: 0,
+            skipped: 0,
+        }
+    }
+
+    fn exec(&mut self, instr: &Instr) {
+        (instr.spec.exec)(self, &instr.args);
+    }
+
+    /// Adds to the total, unless a `don't()` is in effect.
+    fn accumulate(&mut self, value: u64) {
+        if self.enabled {
+            self.total = self.total.and_then(|total| total.checked_add(value));
+            self.counted += 1;
+        } else {
+            self.skipped += 1;
         }
-        candidate
     }
 }
 
-fn consume_multiply(it: &mut Peekable<Chars>) -> u32 {
-    let num1 = match parse_num(it) {
-        Some(num) => num,
-        None => return 0,
-    };
-    if !consume_if(it, ',') {
-        return 0;
+fn explain(text: &str, instructions: &InstructionSet, style: Style) {
+    let (annotations, total) = annotate(instructions, text);
+    print!("{}", render(text, &annotations, style));
+    println!();
+    print!("{}", summary(&annotations, total));
+}
+
+/// How a byte of the input was interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    /// Part of an instruction whose value was added to the total.
+    Counted,
+    /// Part of an instruction whose value was ignored because of a `don't()`.
+    Disabled,
+    /// Part of an instruction that doesn't produce a value, like `do()`.
+    Control,
+    /// An instruction name that isn't followed by a valid argument list.
+    Invalid,
+}
+
+impl Category {
+    const ALL: [Category; 4] = [
+        Category::Counted,
+        Category::Disabled,
+        Category::Control,
+        Category::Invalid,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Counted => "counted",
+            Category::Disabled => "disabled",
+            Category::Control => "control",
+            Category::Invalid => "invalid",
+        }
     }
-    let num2 = match parse_num(it) {
-        Some(num) => num,
-        None => return 0,
-    };
-    if !consume_if(it, ')') {
-        return 0;
+
+    fn color(self) -> &'static str {
+        match self {
+            Category::Counted => "\x1b[1;32m",
+            Category::Disabled => "\x1b[2;33m",
+            Category::Control => "\x1b[36m",
+            Category::Invalid => "\x1b[31m",
+        }
+    }
+
+    fn marker(self) -> char {
+        match self {
+            Category::Counted => '+',
+            Category::Disabled => '-',
+            Category::Control => '=',
+            Category::Invalid => '!',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    /// Instructions are colored in place.
+    Ansi,
+    /// Each line is followed by a line of markers under the instructions.
+    Plain,
+}
+
+#[derive(Debug, PartialEq)]
+struct Annotation {
+    span: Range<usize>,
+    category: Category,
+}
+
+/// Categorizes the instructions and near misses in `text`, in order, and
+/// returns them along with the resulting total.
+fn annotate(instructions: &InstructionSet, text: &str) -> (Vec<Annotation>, Option<u64>) {
+    let bytes = text.as_bytes();
+    let mut annotations = vec![];
+
+    let mut interpreter = Interpreter::new();
+    for token in instructions.tokenize(text) {
+        let (counted, skipped) = (interpreter.counted, interpreter.skipped);
+        interpreter.exec(&token.instr);
+        let category = if interpreter.counted > counted {
+            Category::Counted
+        } else if interpreter.skipped > skipped {
+            Category::Disabled
+        } else {
+            Category::Control
+        };
+        annotations.push(Annotation {
+            span: token.span,
+            category,
+        });
+    }
+
+    // Any other name is a near miss. Include the part of the argument list
+    // that was valid.
+    let mut near_misses = vec![];
+    let mut tokens = annotations.iter().peekable();
+    for m in instructions.build_automaton().find_all(bytes) {
+        while tokens.next_if(|t| t.span.end <= m.start).is_some() {}
+        if tokens.peek().is_some_and(|t| t.span.start < m.end) {
+            continue;
+        }
+        if near_misses
+            .last()
+            .is_some_and(|n: &Annotation| m.start < n.span.end)
+        {
+            continue;
+        }
+        let mut pending = PendingArgs::new(vec![]);
+        let mut end = m.end;
+        while end < bytes.len() {
+            match pending.push(bytes[end], instructions.operands) {
+                ArgStatus::Incomplete => end += 1,
+                ArgStatus::Complete => {
+                    end += 1;
+                    break;
+                }
+                ArgStatus::Invalid => break,
+            }
+        }
+        near_misses.push(Annotation {
+            span: m.start..end,
+            category: Category::Invalid,
+        });
     }
-    num1 * num2
+    annotations.extend(near_misses);
+    annotations.sort_by_key(|a| a.span.start);
+    (annotations, interpreter.total)
 }
 
-fn parse_num(it: &mut Peekable<Chars>) -> Option<u32> {
-    let mut num = 0;
-    let mut is_valid = false;
-    while let Some(Some(d)) = it.peek().map(|c| c.to_digit(10)) {
-        num = num * 10 + d;
-        it.next();
-        is_valid = true;
+fn render(text: &str, annotations: &[Annotation], style: Style) -> String {
+    // Which annotation, if any, covers each byte.
+    let mut covered_by = vec![None; text.len()];
+    for (i, annotation) in annotations.iter().enumerate() {
+        covered_by[annotation.span.clone()].fill(Some(i));
     }
-    if is_valid {
-        Some(num)
-    } else {
-        None
+
+    let mut out = String::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line_covered_by = &covered_by[offset..offset + line.len()];
+        offset += line.len() + 1;
+        match style {
+            Style::Ansi => {
+                let mut current = None;
+                for (i, ch) in line.char_indices() {
+                    let idx = line_covered_by[i];
+                    if idx != current {
+                        if current.is_some() {
+                            out.push_str("\x1b[0m");
+                        }
+                        if let Some(idx) = idx {
+                            out.push_str(annotations[idx].category.color());
+                        }
+                        current = idx;
+                    }
+                    out.push(ch);
+                }
+                if current.is_some() {
+                    out.push_str("\x1b[0m");
+                }
+                out.push('\n');
+            }
+            Style::Plain => {
+                out.push_str(line);
+                out.push('\n');
+                if line_covered_by.iter().any(Option::is_some) {
+                    // One marker per char, so they line up under the text.
+                    let markers = line
+                        .char_indices()
+                        .map(|(i, _)| {
+                            line_covered_by[i].map_or(' ', |idx| annotations[idx].category.marker())
+                        })
+                        .collect::<String>();
+                    out.push_str(markers.trim_end());
+                    out.push('\n');
+                }
+            }
+        }
     }
+    out
+}
+
+fn summary(annotations: &[Annotation], total: Option<u64>) -> String {
+    let mut out = format!("{:<10} {:>6}\n", "Category", "Count");
+    for category in Category::ALL {
+        let count = annotations
+            .iter()
+            .filter(|a| a.category == category)
+            .count();
+        out.push_str(&format!("{:<10} {:>6}\n", category.name(), count));
+    }
+    let total = total.map_or("overflow".to_string(), |total| total.to_string());
+    out.push_str(&format!("{:<10} {:>6}\n", "Total", total));
+    out
+}
+
+/// Multi-pattern matcher with failure links, so every occurrence of every
+/// pattern is found in a single pass over the haystack, including overlapping
+/// ones.
+struct AhoCorasick<'a> {
+    patterns: Vec<&'a str>,
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    next: HashMap<u8, usize>,
+    fail: usize,
+    /// Indices of the patterns ending at this node, longest first.
+    outputs: Vec<usize>,
 }
 
-fn consume_if(it: &mut Peekable<Chars>, val: char) -> bool {
-    match it.peek() {
-        Some(ch) if *ch == val => {
-            it.next();
-            true
+#[derive(Debug, PartialEq)]
+struct Match<'a> {
+    pattern: &'a str,
+    start: usize,
+    end: usize,
+}
+
+impl<'a> AhoCorasick<'a> {
+    const ROOT: usize = 0;
+
+    fn build(patterns: &[&'a str]) -> Self {
+        let mut nodes = vec![Node::default()];
+        for (idx, pattern) in patterns.iter().enumerate() {
+            let mut node = Self::ROOT;
+            for &b in pattern.as_bytes() {
+                node = match nodes[node].next.get(&b) {
+                    Some(&child) => child,
+                    None => {
+                        nodes.push(Node::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].next.insert(b, child);
+                        child
+                    }
+                };
+            }
+            nodes[node].outputs.push(idx);
+        }
+
+        // Breadth-first, so that failure links always point to nodes whose
+        // own links are already final.
+        let mut queue = VecDeque::from([Self::ROOT]);
+        while let Some(node) = queue.pop_front() {
+            let children = nodes[node]
+                .next
+                .iter()
+                .map(|(&b, &child)| (b, child))
+                .collect::<Vec<_>>();
+            for (b, child) in children {
+                let fail = if node == Self::ROOT {
+                    Self::ROOT
+                } else {
+                    Self::transition(&nodes, nodes[node].fail, b)
+                };
+                nodes[child].fail = fail;
+                let inherited = nodes[fail].outputs.clone();
+                nodes[child].outputs.extend(inherited);
+                queue.push_back(child);
+            }
+        }
+
+        Self {
+            patterns: patterns.to_vec(),
+            nodes,
+        }
+    }
+
+    fn transition(nodes: &[Node], mut state: usize, b: u8) -> usize {
+        loop {
+            if let Some(&next) = nodes[state].next.get(&b) {
+                return next;
+            }
+            if state == Self::ROOT {
+                return Self::ROOT;
+            }
+            state = nodes[state].fail;
+        }
+    }
+
+    fn step(&self, state: usize, b: u8) -> usize {
+        Self::transition(&self.nodes, state, b)
+    }
+
+    /// The patterns that end at `state`, longest first.
+    fn outputs(&self, state: usize) -> impl Iterator<Item = &'a str> + '_ {
+        self.nodes[state]
+            .outputs
+            .iter()
+            .map(|&idx| self.patterns[idx])
+    }
+
+    fn find_all(&self, haystack: &[u8]) -> Vec<Match<'a>> {
+        let mut matches = vec![];
+        let mut state = Self::ROOT;
+        for (i, &b) in haystack.iter().enumerate() {
+            state = self.step(state, b);
+            for pattern in self.outputs(state) {
+                matches.push(Match {
+                    pattern,
+                    start: i + 1 - pattern.len(),
+                    end: i + 1,
+                });
+            }
         }
-        _ => false,
+        matches
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io::Cursor;
+
     use super::*;
 
     #[test]
     fn test_multiply() {
         let text = "xmul(2,4)%&mul[3,7]!@^do_not_mul(5,5)+mul(32,64]then(mul(11,8)mul(8,5))";
-        assert_eq!(multiply(text), 161);
+        assert_eq!(multiply(text, Operands::Strict), Some(161));
     }
 
     #[test]
     fn test_do_or_dont_multiply() {
         let text = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
-        assert_eq!(do_or_dont_multiply(text), 48);
+        assert_eq!(do_or_dont_multiply(text, Operands::Strict), Some(48));
+    }
+
+    fn instrs(tokens: &[Token]) -> Vec<(&'static str, Vec<u32>)> {
+        tokens
+            .iter()
+            .map(|t| (t.instr.spec.name, t.instr.args.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let text = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        let tokens = instructions.tokenize(text);
+        assert_eq!(
+            instrs(&tokens),
+            vec![
+                ("mul", vec![2, 4]),
+                ("don't", vec![]),
+                ("mul", vec![5, 5]),
+                ("mul", vec![11, 8]),
+                ("do", vec![]),
+                ("mul", vec![8, 5]),
+            ]
+        );
+        let spans = tokens.iter().map(|t| t.span.clone()).collect::<Vec<_>>();
+        assert_eq!(spans, vec![1..9, 20..27, 28..36, 48..57, 59..63, 64..72]);
+    }
+
+    #[test]
+    fn test_tokenize_rejects_incomplete() {
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        let tokens = instructions.tokenize("do(mul(1,2]don't(x)mul(,3)mul(1)mul(1,2,3)mul(4,5)");
+        assert_eq!(instrs(&tokens), vec![("mul", vec![4, 5])]);
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        let mut interpreter = Interpreter::new();
+        for token in instructions.tokenize("mul(2,3)don't()mul(4,5)do()") {
+            interpreter.exec(&token.instr);
+        }
+        assert_eq!(interpreter.total, Some(6));
+        assert!(interpreter.enabled);
+    }
+
+    #[test]
+    fn test_custom_instructions() {
+        let instructions = InstructionSet::default()
+            .with(MUL)
+            .with(DO)
+            .with(DONT)
+            .with(InstrSpec {
+                name: "add",
+                arity: 2,
+                exec: |interpreter, args| interpreter.accumulate((args[0] + args[1]) as u64),
+            })
+            .with(InstrSpec {
+                name: "mul",
+                arity: 3,
+                exec: |interpreter, args| {
+                    interpreter.accumulate(args.iter().map(|&a| a as u64).product())
+                },
+            })
+            .with(InstrSpec {
+                name: "flip",
+                arity: 0,
+                exec: |interpreter, _| interpreter.enabled = !interpreter.enabled,
+            });
+
+        let text = "add(1,2)mul(2,3,4)flip()mul(5,5)flip()mul(2,3)add(1)";
+        let tokens = instructions.tokenize(text);
+        assert_eq!(
+            instrs(&tokens),
+            vec![
+                ("add", vec![1, 2]),
+                ("mul", vec![2, 3, 4]),
+                ("flip", vec![]),
+                ("mul", vec![5, 5]),
+                ("flip", vec![]),
+                ("mul", vec![2, 3]),
+            ]
+        );
+        assert_eq!(evaluate(&tokens), Some(3 + 24 + 6));
+    }
+
+    #[test]
+    fn test_overlapping_names() {
+        let instructions = InstructionSet::default().with(MUL).with(InstrSpec {
+            name: "xmul",
+            arity: 2,
+            exec: |interpreter, args| interpreter.accumulate((args[0] + args[1]) as u64),
+        });
+        let tokens = instructions.tokenize("xmul(2,3)mul(2,3)");
+        assert_eq!(
+            instrs(&tokens),
+            vec![("xmul", vec![2, 3]), ("mul", vec![2, 3])]
+        );
+    }
+
+    #[test]
+    fn test_scan_matches_in_memory() {
+        let text = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))\
+                    mul(123,456)don't()mul(1,1)do()mul(7,3)";
+        let part1 = InstructionSet::default().with(MUL);
+        let part2 = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        for instructions in [part1, part2] {
+            let expected = evaluate(&instructions.tokenize(text));
+            for chunk_size in 1..=text.len() {
+                let mut reader = Cursor::new(text);
+                let ans = scan(&instructions, &mut reader, chunk_size).unwrap();
+                assert_eq!(Some(ans), expected, "Failed for chunk size {chunk_size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_scanner_carries_state() {
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        let mut scanner = Scanner::new(&instructions);
+        assert!(scanner.feed(b"xmu").is_empty());
+        assert!(scanner.feed(b"l(12").is_empty());
+        let tokens = scanner.feed(b",3)don");
+        assert_eq!(instrs(&tokens), vec![("mul", vec![12, 3])]);
+        assert_eq!(tokens[0].span, 1..10);
+        assert!(scanner.feed(b"'t(").is_empty());
+        let tokens = scanner.feed(b")");
+        assert_eq!(instrs(&tokens), vec![("don't", vec![])]);
+        assert_eq!(tokens[0].span, 10..17);
+    }
+
+    #[test]
+    fn test_strict_operands() {
+        let instructions = InstructionSet::default().with(MUL);
+        let tokens = instructions.tokenize("mul(1,22)mul(999,999)mul(1000,1)mul(1,0001)mul(007,2)");
+        assert_eq!(
+            instrs(&tokens),
+            vec![
+                ("mul", vec![1, 22]),
+                ("mul", vec![999, 999]),
+                ("mul", vec![7, 2])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lenient_operands() {
+        let instructions = InstructionSet::default()
+            .with(MUL)
+            .operands(Operands::Lenient);
+        let tokens = instructions.tokenize("mul(1000,1)mul(4294967295,2)mul(4294967296,1)");
+        assert_eq!(
+            instrs(&tokens),
+            vec![("mul", vec![1000, 1]), ("mul", vec![4294967295, 2])]
+        );
+        assert_eq!(evaluate(&tokens), Some(1000 + 2 * 4294967295));
+    }
+
+    #[test]
+    fn test_total_overflow() {
+        let instructions = InstructionSet::default()
+            .with(MUL)
+            .operands(Operands::Lenient);
+        let text = "mul(4294967295,4294967295)".repeat(2);
+        assert_eq!(evaluate(&instructions.tokenize(&text)), None);
+        let err = scan(&instructions, &mut text.as_bytes(), CHUNK_SIZE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_explain() {
+        let text = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        let (annotations, total) = annotate(&instructions, text);
+        assert_eq!(total, Some(48));
+        assert_eq!(
+            render(text, &annotations, Style::Plain),
+            format!(
+                "{text}\n {}\n",
+                "++++++++ !!!       ======= -------- !!!!!!!!!  ---------  ==== ++++++++"
+            )
+        );
+        assert_eq!(
+            summary(&annotations, total),
+            "Category    Count
+counted         2
+disabled        2
+control         2
+invalid         2
+Total          48
+"
+        );
+    }
+
+    #[test]
+    fn test_explain_ansi() {
+        let text = "mul(1,2)mul(3,4)\nxmul(3\n";
+        let instructions = InstructionSet::default().with(MUL);
+        let (annotations, _) = annotate(&instructions, text);
+        assert_eq!(
+            render(text, &annotations, Style::Ansi),
+            "\x1b[1;32mmul(1,2)\x1b[0m\x1b[1;32mmul(3,4)\x1b[0m\nx\x1b[31mmul(3\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_explain_non_ascii() {
+        let text = "é mul(1,2)x";
+        let instructions = InstructionSet::default().with(MUL);
+        let (annotations, _) = annotate(&instructions, text);
+        assert_eq!(
+            render(text, &annotations, Style::Ansi),
+            "é \x1b[1;32mmul(1,2)\x1b[0mx\n"
+        );
+        assert_eq!(
+            render(text, &annotations, Style::Plain),
+            "é mul(1,2)x\n  ++++++++\n"
+        );
     }
 
     #[test]
-    fn test_trie_match() {
-        let trie = Trie::build(&["a", "abc", "def"]);
+    fn test_aho_corasick_find_all() {
+        let automaton = AhoCorasick::build(&["a", "abc", "def"]);
 
         let cases = [
-            ("x", None),
-            ("a", Some("a")),
-            ("ax", Some("a")),
-            ("abc", Some("abc")),
-            ("abcd", Some("abc")),
-            ("de", None),
-            ("def", Some("def")),
+            ("x", vec![]),
+            ("a", vec![("a", 0)]),
+            ("ax", vec![("a", 0)]),
+            ("abc", vec![("a", 0), ("abc", 0)]),
+            ("abxabc", vec![("a", 0), ("a", 3), ("abc", 3)]),
+            ("de", vec![]),
+            ("ddef", vec![("def", 1)]),
         ];
         for (haystack, expected) in cases {
-            let mut it = haystack.chars().peekable();
-            assert_eq!(trie.find(&mut it), expected, "Failed for \"{}\"", haystack);
+            let found = automaton
+                .find_all(haystack.as_bytes())
+                .into_iter()
+                .map(|m| (m.pattern, m.start))
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "Failed for \"{}\"", haystack);
         }
     }
 
     #[test]
-    fn test_trie_consume() {
-        let trie = Trie::build(&["a", "abc", "def"]);
-
-        let haystack = "dexyz";
-        let mut it = haystack.chars().peekable();
-        assert_eq!(trie.find(&mut it), None);
-        assert_eq!(it.next(), Some('x'));
+    fn test_aho_corasick_overlapping() {
+        let automaton = AhoCorasick::build(&["he", "she", "hers", "his"]);
+        let found = automaton.find_all(b"ushers");
+        assert_eq!(
+            found,
+            vec![
+                Match {
+                    pattern: "she",
+                    start: 1,
+                    end: 4
+                },
+                Match {
+                    pattern: "he",
+                    start: 2,
+                    end: 4
+                },
+                Match {
+                    pattern: "hers",
+                    start: 2,
+                    end: 6
+                },
+            ]
+        );
+    }
 
-        let haystack = "defgh";
-        let mut it = haystack.chars().peekable();
-        assert_eq!(trie.find(&mut it), Some("def"));
-        assert_eq!(it.next(), Some('g'));
+    #[test]
+    fn test_do_or_dont_after_partial_match() {
+        // "do" is a prefix of "don't(", so the automaton must fall back to
+        // "do(" without skipping it.
+        let instructions = InstructionSet::default().with(MUL).with(DO).with(DONT);
+        for text in ["don't()dodo()mul(2,3)", "mumul(2,3)"] {
+            assert_eq!(do_or_dont_multiply(text, Operands::Strict), Some(6));
+            assert_eq!(scan(&instructions, &mut text.as_bytes(), 1).unwrap(), 6);
+        }
+    }
 
-        let haystack = "xyz";
-        let mut it = haystack.chars().peekable();
-        assert_eq!(trie.find(&mut it), None);
-        assert_eq!(it.peek(), Some(&'y'));
-        assert_eq!(trie.find(&mut it), None);
-        assert_eq!(it.peek(), Some(&'z'));
-        assert_eq!(trie.find(&mut it), None);
-        assert_eq!(it.peek(), None);
+    #[test]
+    fn test_scanner_matches_tokenize() {
+        let xmul = InstrSpec {
+            name: "xmul",
+            arity: 2,
+            exec: |interpreter, args| interpreter.accumulate((args[0] + args[1]) as u64),
+        };
+        let mul3 = InstrSpec {
+            name: "mul",
+            arity: 3,
+            exec: |interpreter, args| interpreter.accumulate(args[0] as u64),
+        };
+        let sets = [
+            InstructionSet::default().with(MUL).with(DO).with(DONT),
+            InstructionSet::default().with(MUL).with(xmul).with(mul3),
+            InstructionSet::default()
+                .with(MUL)
+                .with(DO)
+                .with(DONT)
+                .operands(Operands::Lenient),
+        ];
+        let texts = [
+            "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))",
+            "xmul(2,3)mul(2,3)xxmul(1,1)mumul(4,5)",
+            "mul(1,2,3)mul(1,2,3,4)mul(1,2)mul(1,)mul(,1)",
+            "don't()dodo()do(don't(mul(2,3)don't()do()",
+            "mul(1000,1)mul(0001,2)mul(007,2)",
+            "mul(4294967295,2)mul(4294967296,1)mul(99999999999,3)mul(1,1)",
+            "mul(4294967296mul(1,2)mul(42949672950,mul(3,4)",
+        ];
+        for instructions in &sets {
+            for text in texts {
+                let expected = instructions
+                    .tokenize(text)
+                    .into_iter()
+                    .map(|t| (t.instr.spec.name, t.instr.args, t.span))
+                    .collect::<Vec<_>>();
+                for chunk_size in 1..=text.len() {
+                    let mut scanner = Scanner::new(instructions);
+                    let tokens = text
+                        .as_bytes()
+                        .chunks(chunk_size)
+                        .flat_map(|chunk| scanner.feed(chunk))
+                        .map(|t| (t.instr.spec.name, t.instr.args, t.span))
+                        .collect::<Vec<_>>();
+                    assert_eq!(tokens, expected, "{text:?} in chunks of {chunk_size}");
+                }
+            }
+        }
     }
 }
@@ -1,19 +1,220 @@
-pub fn solve() {
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+};
+
+use crate::trie::{Trie, TrieNode};
+
+pub fn solve(args: &[String]) {
+    if args.iter().any(|arg| arg == "--stream") {
+        // Only a few rows are held at a time, so the grid may be larger than
+        // memory.
+        let counts = File::open("inputs/day4.txt").and_then(|file| {
+            let xmas = stream_count_word(BufReader::new(file), &to_word("XMAS"))?;
+            let file = File::open("inputs/day4.txt")?;
+            let pattern = Pattern::parse("M.S/.A./M.S");
+            let cross_mas = stream_count_pattern(BufReader::new(file), &pattern)?;
+            Ok((xmas, cross_mas))
+        });
+        match counts {
+            Ok((xmas, cross_mas)) => {
+                println!("Part 1: {xmas}");
+                println!("Part 2: {cross_mas}");
+            }
+            Err(err) => eprintln!("Invalid input: {err}"),
+        }
+        return;
+    }
     let text = std::fs::read_to_string("inputs/day4.txt").unwrap();
-    let grid = to_grid(text.lines());
+    if args.iter().any(|arg| arg == "--cube") {
+        match NdGrid::parse_layers(&text) {
+            Ok(cube) => println!("Part 1: {}", cube.count_word(&to_word("XMAS"))),
+            Err(err) => eprintln!("Invalid input: {err}"),
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--packed") {
+        // Skips building the `Vec<Vec<char>>` grid, which is much larger.
+        match PackedGrid::parse(&text) {
+            Ok(grid) => println!("Part 1: {}", grid.count_word(b"XMAS")),
+            Err(err) => eprintln!("Invalid input: {err}"),
+        }
+        return;
+    }
+    let grid = match to_grid(text.lines()) {
+        Ok(grid) => grid,
+        Err(err) => {
+            eprintln!("Invalid input: {err}");
+            return;
+        }
+    };
+    if let Some(i) = args.iter().position(|arg| arg == "--words") {
+        let Some(words) = args.get(i + 1) else {
+            eprintln!("Usage: --words WORD1,WORD2,...");
+            return;
+        };
+        let words = words.split(',').map(to_word).collect::<Vec<_>>();
+        let options = SearchOptions {
+            dirs: if args.iter().any(|arg| arg == "--orthogonal") {
+                Dirs::Orthogonal
+            } else if args.iter().any(|arg| arg == "--diagonal") {
+                Dirs::Diagonal
+            } else {
+                Dirs::All
+            },
+            overlaps: !args.iter().any(|arg| arg == "--no-overlaps"),
+        };
+        let matches = find_words(&grid, &words, options);
+        for m in &matches {
+            let word = m.word.iter().collect::<String>();
+            println!("{word} at {:?} going {:?}", m.start, m.dir);
+        }
+        println!("Found: {}", matches.len());
+        return;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--boggle") {
+        let Some(path) = args.get(i + 1) else {
+            eprintln!("Usage: --boggle DICTIONARY_FILE");
+            return;
+        };
+        let dictionary = match std::fs::read_to_string(path) {
+            Ok(dictionary) => dictionary,
+            Err(err) => {
+                eprintln!("Can't read dictionary: {err}");
+                return;
+            }
+        };
+        let mut trie = Trie::new();
+        for word in dictionary.lines().map(str::trim).filter(|w| !w.is_empty()) {
+            trie.insert(word.chars());
+        }
+        let matches = find_paths(&grid, &trie);
+        for m in &matches {
+            println!("{} along {:?}", m.word, m.path);
+        }
+        println!("Found: {}", matches.len());
+        return;
+    }
+    if args.iter().any(|arg| arg == "--render") {
+        let style = if args.iter().any(|arg| arg == "--dots") {
+            Style::Dots
+        } else {
+            Style::Ansi
+        };
+        let by_dir = args.iter().any(|arg| arg == "--by-dir");
+        render_matches(&grid, style, by_dir);
+    }
     println!("Part 1: {}", count_xmas(&grid));
     println!("Part 2: {}", count_cross_mas(&grid));
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    /// Matched letters are highlighted in color.
+    Ansi,
+    /// Letters that aren't part of any match are replaced with `.`, as in the
+    /// puzzle description.
+    Dots,
+}
+
+fn render_matches(grid: &Grid, style: Style, by_dir: bool) {
+    let xmas = [to_word("XMAS")];
+    let matches = find_words(grid, &xmas, SearchOptions::default());
+    if by_dir {
+        for dir in DIRS {
+            let cells = matches
+                .iter()
+                .filter(|m| m.dir == dir)
+                .flat_map(WordMatch::cells)
+                .collect::<Vec<_>>();
+            let count = matches.iter().filter(|m| m.dir == dir).count();
+            println!("XMAS going {dir:?}: {count}");
+            print!("{}", render(grid, &cells, style));
+            println!();
+        }
+    } else {
+        let cells = matches
+            .iter()
+            .flat_map(WordMatch::cells)
+            .collect::<Vec<_>>();
+        println!("XMAS: {}", matches.len());
+        print!("{}", render(grid, &cells, style));
+        println!();
+    }
+
+    let cross_mas = find_pattern(grid, &Pattern::parse("M.S/.A./M.S"));
+    println!("X-MAS: {}", cross_mas.len());
+    print!("{}", render(grid, &cross_mas.concat(), style));
+    println!();
+}
+
+/// Draws the grid with the given cells highlighted.
+fn render(grid: &Grid, cells: &[Point], style: Style) -> String {
+    let mut highlighted = vec![vec![false; width(grid)]; grid.len()];
+    for &(x, y) in cells {
+        highlighted[x as usize][y as usize] = true;
+    }
+    let mut out = String::new();
+    for (row, highlighted) in grid.iter().zip(highlighted) {
+        for (&ch, highlighted) in row.iter().zip(highlighted) {
+            match (style, highlighted) {
+                (Style::Ansi, true) => out.push_str(&format!("\x1b[1;32m{ch}\x1b[0m")),
+                (Style::Ansi, false) => out.push(ch),
+                (Style::Dots, true) => out.push(ch),
+                (Style::Dots, false) => out.push('.'),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
 type Grid = Vec<Vec<char>>;
 type Word = Vec<char>;
 type Point = (i32, i32);
 
-fn to_grid<'a, I>(lines: I) -> Grid
+/// A row whose length differs from the first row's. Line numbers are 1-based.
+#[derive(Debug, PartialEq)]
+struct RaggedRow {
+    line: usize,
+    expected: usize,
+    found: usize,
+}
+
+impl fmt::Display for RaggedRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: expected {} letters, found {}",
+            self.line, self.expected, self.found
+        )
+    }
+}
+
+impl Error for RaggedRow {}
+
+/// Builds a rectangular grid. There may be no rows at all.
+fn to_grid<'a, I>(lines: I) -> Result<Grid, RaggedRow>
 where
     I: IntoIterator<Item = &'a str>,
 {
-    lines.into_iter().map(to_word).collect()
+    let grid = lines.into_iter().map(to_word).collect::<Grid>();
+    for (i, row) in grid.iter().enumerate() {
+        if row.len() != grid[0].len() {
+            return Err(RaggedRow {
+                line: i + 1,
+                expected: grid[0].len(),
+                found: row.len(),
+            });
+        }
+    }
+    Ok(grid)
+}
+
+fn width(grid: &Grid) -> usize {
+    grid.first().map_or(0, Vec::len)
 }
 
 fn to_word(text: &str) -> Word {
@@ -31,65 +232,657 @@ const DIRS: [(i32, i32); 8] = [
     (1, 1),
 ];
 
-fn count_xmas(grid: &Grid) -> usize {
-    let mut ans = 0;
-    let xmas = to_word("XMAS");
+/// Which of `DIRS` to search in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Dirs {
+    #[default]
+    All,
+    Orthogonal,
+    Diagonal,
+}
+
+impl Dirs {
+    fn contains(self, dir: Point) -> bool {
+        let diagonal = dir.0 != 0 && dir.1 != 0;
+        match self {
+            Dirs::All => true,
+            Dirs::Orthogonal => !diagonal,
+            Dirs::Diagonal => diagonal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SearchOptions {
+    dirs: Dirs,
+    /// Whether a cell can be part of more than one match. If not, matches are
+    /// accepted in the order they are found, and later ones sharing a cell
+    /// with an accepted match are dropped.
+    overlaps: bool,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            dirs: Dirs::All,
+            overlaps: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct WordMatch<'a> {
+    word: &'a Word,
+    start: Point,
+    /// `(0, 0)` for single letter words, which have no direction.
+    dir: Point,
+}
+
+impl WordMatch<'_> {
+    fn cells(&self) -> impl Iterator<Item = Point> + '_ {
+        (0..self.word.len() as i32)
+            .map(|i| (self.start.0 + i * self.dir.0, self.start.1 + i * self.dir.1))
+    }
+}
+
+/// Finds the words in straight lines, scanning the grid row by row, and
+/// trying the words in the given order at each cell.
+fn find_words<'a>(grid: &Grid, words: &'a [Word], options: SearchOptions) -> Vec<WordMatch<'a>> {
+    let mut matches = vec![];
+    let mut used = vec![vec![false; width(grid)]; grid.len()];
     for x in 0..grid.len() {
-        for y in 0..grid[0].len() {
-            ans += count_words_at(grid, &xmas, (x as i32, y as i32));
+        for y in 0..width(grid) {
+            let start = (x as i32, y as i32);
+            for word in words {
+                for dir in dirs_at(grid, word, start, options.dirs) {
+                    let m = WordMatch { word, start, dir };
+                    if !options.overlaps {
+                        if m.cells().any(|(i, j)| used[i as usize][j as usize]) {
+                            continue;
+                        }
+                        m.cells()
+                            .for_each(|(i, j)| used[i as usize][j as usize] = true);
+                    }
+                    matches.push(m);
+                }
+            }
         }
     }
-    ans
+    matches
+}
+
+fn count_xmas(grid: &Grid) -> usize {
+    find_words(grid, &[to_word("XMAS")], SearchOptions::default()).len()
 }
 
 fn count_cross_mas(grid: &Grid) -> usize {
-    let mut count = 0;
-    let pairs = [('M', 'S'), ('S', 'M')];
-    for i in 1..(grid.len() - 1) {
-        for j in 1..(grid[0].len() - 1) {
-            if grid[i][j] == 'A' {
-                let p1 = (grid[i - 1][j - 1], grid[i + 1][j + 1]);
-                let p2 = (grid[i + 1][j - 1], grid[i - 1][j + 1]);
-                if pairs.contains(&p1) && pairs.contains(&p2) {
-                    count += 1;
+    count_pattern(grid, &Pattern::parse("M.S/.A./M.S"))
+}
+
+/// A rectangle of letters, where `None` matches any letter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    cells: Vec<Vec<Option<char>>>,
+}
+
+impl Pattern {
+    /// Parses rows separated by `/` or newlines, with `.` as the wildcard.
+    fn parse(art: &str) -> Self {
+        let cells = art
+            .split(['/', '\n'])
+            .map(|row| {
+                row.chars()
+                    .map(|ch| (ch != '.').then_some(ch))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            !cells[0].is_empty() && cells.iter().all(|row| row.len() == cells[0].len()),
+            "pattern must be a non-empty rectangle: {art:?}"
+        );
+        Self { cells }
+    }
+
+    fn height(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn width(&self) -> usize {
+        self.cells[0].len()
+    }
+
+    /// Rotates a quarter turn clockwise.
+    fn rotate(&self) -> Self {
+        let cells = (0..self.width())
+            .map(|j| (0..self.height()).rev().map(|i| self.cells[i][j]).collect())
+            .collect();
+        Self { cells }
+    }
+
+    /// Mirrors left to right.
+    fn reflect(&self) -> Self {
+        let cells = self
+            .cells
+            .iter()
+            .map(|row| row.iter().rev().copied().collect())
+            .collect();
+        Self { cells }
+    }
+
+    /// The distinct patterns among the 4 rotations of the pattern and of its
+    /// mirror image.
+    fn symmetries(&self) -> Vec<Self> {
+        let mut patterns: Vec<Self> = vec![];
+        for mut pattern in [self.clone(), self.reflect()] {
+            for _ in 0..4 {
+                if !patterns.contains(&pattern) {
+                    patterns.push(pattern.clone());
+                }
+                pattern = pattern.rotate();
+            }
+        }
+        patterns
+    }
+
+    /// The cells covered by letters when the top left corner is at `start`.
+    fn letter_cells(&self, start: Point) -> Vec<Point> {
+        let mut cells = vec![];
+        for (i, row) in self.cells.iter().enumerate() {
+            for (j, cell) in row.iter().enumerate() {
+                if cell.is_some() {
+                    cells.push((start.0 + i as i32, start.1 + j as i32));
+                }
+            }
+        }
+        cells
+    }
+
+    /// Whether the pattern matches with its top left corner at `(x, y)`.
+    fn matches_at(&self, grid: &Grid, (x, y): (usize, usize)) -> bool {
+        self.cells.iter().enumerate().all(|(i, row)| {
+            row.iter().enumerate().all(|(j, cell)| {
+                cell.is_none_or(|ch| grid.get(x + i).and_then(|r| r.get(y + j)) == Some(&ch))
+            })
+        })
+    }
+}
+
+/// Counts the matches of the pattern in any orientation.
+fn count_pattern(grid: &Grid, pattern: &Pattern) -> usize {
+    find_pattern(grid, pattern).len()
+}
+
+/// Finds the matches of the pattern in any orientation, returning the cells
+/// matched by letters (not wildcards) of each.
+fn find_pattern(grid: &Grid, pattern: &Pattern) -> Vec<Vec<Point>> {
+    let mut matches = vec![];
+    for pattern in pattern.symmetries() {
+        for x in 0..(grid.len() + 1).saturating_sub(pattern.height()) {
+            for y in 0..(width(grid) + 1).saturating_sub(pattern.width()) {
+                if pattern.matches_at(grid, (x, y)) {
+                    matches.push(pattern.letter_cells((x as i32, y as i32)));
                 }
             }
         }
     }
-    count
+    matches
 }
 
 fn exists_in_dir(grid: &Grid, word: &Word, start: Point, dir: Point) -> bool {
-    let (mut x, mut y) = start;
-    for ch in word {
-        if x < 0 || x >= grid.len() as i32 || y < 0 || y >= grid[0].len() as i32 {
-            return false;
+    let letters = (0..).map(|i| letter_at(grid, (start.0 + i * dir.0, start.1 + i * dir.1)));
+    spells(word, letters)
+}
+
+fn letter_at(grid: &Grid, (x, y): Point) -> Option<char> {
+    let row = grid.get(usize::try_from(x).ok()?)?;
+    row.get(usize::try_from(y).ok()?).copied()
+}
+
+/// Whether the letters read one step at a time start with `word`. `None`
+/// stands for a step off the grid.
+fn spells(word: &Word, mut letters: impl Iterator<Item = Option<char>>) -> bool {
+    word.iter().all(|&ch| letters.next().flatten() == Some(ch))
+}
+
+/// The directions in which `word` can be read from `start`.
+fn dirs_at(grid: &Grid, word: &Word, start: Point, dirs: Dirs) -> Vec<Point> {
+    if word.first() != Some(&grid[start.0 as usize][start.1 as usize]) {
+        return vec![];
+    }
+    if word.len() == 1 {
+        return vec![(0, 0)];
+    }
+    DIRS.into_iter()
+        .filter(|&dir| dirs.contains(dir) && exists_in_dir(grid, word, start, dir))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct PathMatch {
+    word: String,
+    /// One cell per letter, each a neighbour (in any of the 8 directions) of
+    /// the one before.
+    path: Vec<Point>,
+}
+
+/// Finds the words of the dictionary along paths that may turn at every
+/// letter, Boggle style, without using a cell twice in one word. Every path
+/// spelling a word is returned, so a word can be found more than once. The
+/// search follows the trie, abandoning a path as soon as no word starts with
+/// its letters.
+fn find_paths(grid: &Grid, dictionary: &Trie<char>) -> Vec<PathMatch> {
+    let mut matches = vec![];
+    let mut visited = vec![vec![false; width(grid)]; grid.len()];
+    let mut path = vec![];
+    for x in 0..grid.len() {
+        for y in 0..width(grid) {
+            let start = (x as i32, y as i32);
+            extend_path(
+                grid,
+                dictionary.root(),
+                start,
+                &mut visited,
+                &mut path,
+                &mut matches,
+            );
+        }
+    }
+    matches
+}
+
+fn extend_path(
+    grid: &Grid,
+    node: &TrieNode<char>,
+    cell: Point,
+    visited: &mut Vec<Vec<bool>>,
+    path: &mut Vec<Point>,
+    matches: &mut Vec<PathMatch>,
+) {
+    let (x, y) = cell;
+    if x < 0 || x >= grid.len() as i32 || y < 0 || y >= width(grid) as i32 {
+        return;
+    }
+    if visited[x as usize][y as usize] {
+        return;
+    }
+    let Some(node) = node.child(&grid[x as usize][y as usize]) else {
+        return;
+    };
+
+    visited[x as usize][y as usize] = true;
+    path.push(cell);
+    if node.is_word() {
+        matches.push(PathMatch {
+            word: path
+                .iter()
+                .map(|&(i, j)| grid[i as usize][j as usize])
+                .collect(),
+            path: path.clone(),
+        });
+    }
+    for (dx, dy) in DIRS {
+        extend_path(grid, node, (x + dx, y + dy), visited, path, matches);
+    }
+    path.pop();
+    visited[x as usize][y as usize] = false;
+}
+
+/// The last few rows read from a grid, oldest first. Ragged rows are rejected
+/// as they arrive, like `to_grid` does.
+struct RowWindow {
+    rows: Grid,
+    capacity: usize,
+    lines: usize,
+}
+
+impl RowWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            rows: Vec::with_capacity(capacity),
+            capacity,
+            lines: 0,
+        }
+    }
+
+    /// Appends a row, dropping the oldest one if the window is full.
+    fn push(&mut self, line: &str) -> Result<(), RaggedRow> {
+        let row = to_word(line);
+        self.lines += 1;
+        if let Some(first) = self.rows.first() {
+            if row.len() != first.len() {
+                return Err(RaggedRow {
+                    line: self.lines,
+                    expected: first.len(),
+                    found: row.len(),
+                });
+            }
+        }
+        if self.rows.len() == self.capacity {
+            self.rows.remove(0);
+        }
+        self.rows.push(row);
+        Ok(())
+    }
+
+    /// Counts the occurrences of `word` whose lowest letter is in the newest
+    /// row, so that each occurrence is counted once, when its last row
+    /// arrives. The window must hold at least `word.len()` rows.
+    fn count_word_ending(&self, word: &Word) -> usize {
+        let Some(last) = self.rows.len().checked_sub(1) else {
+            return 0;
+        };
+        let span = word.len().saturating_sub(1);
+        // Words going up start in the newest row, and words going down start
+        // `span` rows above it.
+        let mut start_rows = vec![last];
+        if span > 0 && span <= last {
+            start_rows.push(last - span);
         }
 
-        if *ch != grid[x as usize][y as usize] {
-            return false;
+        let mut count = 0;
+        for x in start_rows {
+            for y in 0..width(&self.rows) {
+                let start = (x as i32, y as i32);
+                count += dirs_at(&self.rows, word, start, Dirs::All)
+                    .into_iter()
+                    .filter(|dir| x as i32 + span as i32 * dir.0.max(0) == last as i32)
+                    .count();
+            }
         }
+        count
+    }
 
-        x += dir.0;
-        y += dir.1;
+    /// Counts the matches of the patterns whose bottom row is the newest row.
+    fn count_patterns_ending(&self, patterns: &[Pattern]) -> usize {
+        let mut count = 0;
+        for pattern in patterns {
+            let Some(x) = self.rows.len().checked_sub(pattern.height()) else {
+                continue;
+            };
+            for y in 0..(width(&self.rows) + 1).saturating_sub(pattern.width()) {
+                if pattern.matches_at(&self.rows, (x, y)) {
+                    count += 1;
+                }
+            }
+        }
+        count
     }
-    true
 }
 
-fn count_words_at(grid: &Grid, word: &Word, start: Point) -> usize {
-    if grid[start.0 as usize][start.1 as usize] != word[0] {
-        return 0;
+/// Reads the rows one at a time, calling `count` with each new window.
+fn stream_rows<R, F>(reader: R, capacity: usize, mut count: F) -> io::Result<usize>
+where
+    R: BufRead,
+    F: FnMut(&RowWindow) -> usize,
+{
+    let mut window = RowWindow::new(capacity);
+    let mut total = 0;
+    for line in reader.lines() {
+        window
+            .push(&line?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        total += count(&window);
     }
-    if word.len() == 1 {
-        return 1;
+    Ok(total)
+}
+
+/// Counts `word` in straight lines like `find_words` with the default
+/// options, holding only `word.len()` rows in memory.
+fn stream_count_word<R: BufRead>(reader: R, word: &Word) -> io::Result<usize> {
+    stream_rows(reader, word.len().max(1), |window| {
+        window.count_word_ending(word)
+    })
+}
+
+/// Counts the pattern in any orientation like `count_pattern`, holding only as
+/// many rows as its tallest orientation.
+fn stream_count_pattern<R: BufRead>(reader: R, pattern: &Pattern) -> io::Result<usize> {
+    let patterns = pattern.symmetries();
+    let height = patterns.iter().map(Pattern::height).max().unwrap_or(1);
+    stream_rows(reader, height, |window| {
+        window.count_patterns_ending(&patterns)
+    })
+}
+
+/// A grid of letters with any number of dimensions, stored flat in row-major
+/// order: the last coordinate varies fastest.
+#[derive(Debug, PartialEq)]
+struct NdGrid {
+    shape: Vec<usize>,
+    cells: Vec<char>,
+}
+
+/// Layers of a cube that don't all have the same size.
+#[derive(Debug, PartialEq)]
+enum InvalidCube {
+    RaggedRow(RaggedRow),
+    /// A layer, starting on `line`, with the wrong number of rows.
+    RaggedLayer {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for InvalidCube {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RaggedRow(err) => err.fmt(f),
+            Self::RaggedLayer {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {line}: expected a layer of {expected} rows, found {found}"
+            ),
+        }
+    }
+}
+
+impl Error for InvalidCube {}
+
+impl NdGrid {
+    /// Parses a 3D grid from layers of rows separated by blank lines. The
+    /// first coordinate is the layer.
+    fn parse_layers(text: &str) -> Result<Self, InvalidCube> {
+        let mut layers: Vec<(usize, Grid)> = vec![];
+        let mut in_layer = false;
+        for (i, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                in_layer = false;
+                continue;
+            }
+            if !in_layer {
+                layers.push((i + 1, vec![]));
+                in_layer = true;
+            }
+            let row = to_word(line);
+            let expected = layers[0].1.first().map_or(row.len(), Vec::len);
+            if row.len() != expected {
+                return Err(InvalidCube::RaggedRow(RaggedRow {
+                    line: i + 1,
+                    expected,
+                    found: row.len(),
+                }));
+            }
+            layers.last_mut().unwrap().1.push(row);
+        }
+
+        let rows = layers.first().map_or(0, |(_, layer)| layer.len());
+        for (line, layer) in &layers {
+            if layer.len() != rows {
+                return Err(InvalidCube::RaggedLayer {
+                    line: *line,
+                    expected: rows,
+                    found: layer.len(),
+                });
+            }
+        }
+        let cols = layers.first().map_or(0, |(_, layer)| width(layer));
+        Ok(Self {
+            shape: vec![layers.len(), rows, cols],
+            cells: layers
+                .iter()
+                .flat_map(|(_, layer)| layer.concat())
+                .collect(),
+        })
+    }
+
+    fn get(&self, point: &[i32]) -> Option<char> {
+        let mut index = 0;
+        for (&coord, &len) in point.iter().zip(&self.shape) {
+            let coord = usize::try_from(coord).ok().filter(|&c| c < len)?;
+            index = index * len + coord;
+        }
+        self.cells.get(index).copied()
+    }
+
+    /// The coordinates of the cell stored at `index`.
+    fn point(&self, mut index: usize) -> Vec<i32> {
+        let mut point = vec![0; self.shape.len()];
+        for (coord, &len) in point.iter_mut().zip(&self.shape).rev() {
+            *coord = (index % len) as i32;
+            index /= len;
+        }
+        point
+    }
+
+    /// Counts the occurrences of `word` in straight lines in all `3^N - 1`
+    /// directions, like `count_xmas` does in 2D.
+    fn count_word(&self, word: &Word) -> usize {
+        if word.len() == 1 {
+            return self.cells.iter().filter(|&&ch| ch == word[0]).count();
+        }
+        let dirs = nd_dirs(self.shape.len());
+        let mut count = 0;
+        for (index, &ch) in self.cells.iter().enumerate() {
+            if word.first() != Some(&ch) {
+                continue;
+            }
+            let start = self.point(index);
+            for dir in &dirs {
+                let letters = (0..).map(|i| {
+                    let point = start
+                        .iter()
+                        .zip(dir)
+                        .map(|(x, dx)| x + i * dx)
+                        .collect::<Vec<_>>();
+                    self.get(&point)
+                });
+                if spells(word, letters) {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+}
+
+/// Every step of -1, 0 or 1 along each of `n` axes, except standing still.
+/// In 2D, these are `DIRS`.
+fn nd_dirs(n: usize) -> Vec<Vec<i32>> {
+    let mut dirs = vec![vec![]];
+    for _ in 0..n {
+        dirs = dirs
+            .into_iter()
+            .flat_map(|dir: Vec<i32>| {
+                [-1, 0, 1].map(|step| {
+                    let mut dir = dir.clone();
+                    dir.push(step);
+                    dir
+                })
+            })
+            .collect();
+    }
+    dirs.retain(|dir| dir.iter().any(|&step| step != 0));
+    dirs
+}
+
+/// A grid stored as one byte per cell in a single allocation, for large
+/// inputs. Rows are separated by a sentinel column, and there are sentinel
+/// rows above and below, so every straight line through the grid starts and
+/// ends next to a sentinel and can be walked without checking coordinates.
+struct PackedGrid {
+    cells: Vec<u8>,
+    rows: usize,
+    cols: usize,
+}
+
+impl PackedGrid {
+    const SENTINEL: u8 = 0;
+
+    /// Fails on ragged rows, like `to_grid`.
+    fn parse(text: &str) -> Result<Self, RaggedRow> {
+        let cols = text.lines().next().map_or(0, str::len);
+        let stride = cols + 1;
+        let mut cells = vec![Self::SENTINEL; stride];
+        let mut rows = 0;
+        for (i, line) in text.lines().enumerate() {
+            if line.len() != cols {
+                return Err(RaggedRow {
+                    line: i + 1,
+                    expected: cols,
+                    found: line.len(),
+                });
+            }
+            cells.push(Self::SENTINEL);
+            cells.extend_from_slice(line.as_bytes());
+            rows += 1;
+        }
+        // The bottom row is one longer, so that stepping down and right from
+        // the last cell stays inside the buffer.
+        cells.resize(cells.len() + stride + 1, Self::SENTINEL);
+        Ok(Self { cells, rows, cols })
+    }
+
+    fn stride(&self) -> usize {
+        self.cols + 1
     }
-    let mut count = 0;
-    for dir in DIRS {
-        if exists_in_dir(grid, word, start, dir) {
-            count += 1;
+
+    fn index(&self, row: usize, col: usize) -> usize {
+        (row + 1) * self.stride() + col + 1
+    }
+
+    /// Counts the occurrences of `word` in straight lines in all 8
+    /// directions, like `count_xmas`.
+    fn count_word(&self, word: &[u8]) -> usize {
+        match word.len() {
+            0 => return 0,
+            1 => return self.cells.iter().filter(|&&b| b == word[0]).count(),
+            _ => {}
+        }
+        let reversed = word.iter().rev().copied().collect::<Vec<_>>();
+        let stride = self.stride();
+        // Right, down, down-right and down-left. Reading each line backwards
+        // covers the other 4 directions.
+        let steps = [1, stride, stride + 1, stride - 1];
+
+        let mut count = 0;
+        let mut line = Vec::with_capacity(self.rows.max(self.cols));
+        for step in steps {
+            for start in self.line_starts(step) {
+                line.clear();
+                let mut idx = start;
+                while self.cells[idx] != Self::SENTINEL {
+                    line.push(self.cells[idx]);
+                    idx += step;
+                }
+                // A palindrome counts once for each direction.
+                for w in line.windows(word.len()) {
+                    count += (w == word) as usize + (w == reversed) as usize;
+                }
+            }
         }
+        count
+    }
+
+    /// The cells whose predecessor in the direction of `step` is a sentinel.
+    fn line_starts(&self, step: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.rows)
+            .flat_map(move |row| (0..self.cols).map(move |col| self.index(row, col)))
+            .filter(move |&idx| self.cells[idx - step] == Self::SENTINEL)
     }
-    count
 }
 
 #[cfg(test)]
@@ -98,7 +891,7 @@ mod test {
 
     #[test]
     fn test_exists_in_dir() {
-        let grid = to_grid(["ABC", "ABC", "ABC"]);
+        let grid = to_grid(["ABC", "ABC", "ABC"]).unwrap();
 
         assert!(exists_in_dir(&grid, &to_word("A"), (0, 0), (1, 1)));
         assert!(exists_in_dir(&grid, &to_word("AB"), (0, 0), (0, 1)));
@@ -107,11 +900,11 @@ mod test {
     }
 
     #[test]
-    fn test_count_words_at() {
-        let grid = to_grid(["ABCDE", "ABCDE"]);
-        assert_eq!(count_words_at(&grid, &to_word("X"), (0, 0)), 0);
-        assert_eq!(count_words_at(&grid, &to_word("A"), (0, 0)), 1);
-        assert_eq!(count_words_at(&grid, &to_word("AB"), (0, 0)), 2);
+    fn test_dirs_at() {
+        let grid = to_grid(["ABCDE", "ABCDE"]).unwrap();
+        assert_eq!(dirs_at(&grid, &to_word("X"), (0, 0), Dirs::All).len(), 0);
+        assert_eq!(dirs_at(&grid, &to_word("A"), (0, 0), Dirs::All).len(), 1);
+        assert_eq!(dirs_at(&grid, &to_word("AB"), (0, 0), Dirs::All).len(), 2);
     }
 
     #[test]
@@ -128,7 +921,7 @@ mod test {
             "MAMMMXMMMM",
             "MXMXAXMASX",
         ];
-        assert_eq!(count_xmas(&to_grid(rows)), 18);
+        assert_eq!(count_xmas(&to_grid(rows).unwrap()), 18);
     }
 
     #[test]
@@ -145,6 +938,418 @@ mod test {
             "MAMMMXMMMM",
             "MXMXAXMASX",
         ];
-        assert_eq!(count_cross_mas(&to_grid(rows)), 9);
+        assert_eq!(count_cross_mas(&to_grid(rows).unwrap()), 9);
+    }
+
+    #[test]
+    fn test_find_words() {
+        let grid = to_grid(["XMAS", "MM..", "A.A.", "S..S"]).unwrap();
+        let words = [to_word("XMAS"), to_word("SAMX")];
+
+        let matches = find_words(&grid, &words, SearchOptions::default());
+        let found = matches
+            .iter()
+            .map(|m| (m.word.iter().collect::<String>(), m.start, m.dir))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("XMAS".to_string(), (0, 0), (0, 1)),
+                ("XMAS".to_string(), (0, 0), (1, 0)),
+                ("XMAS".to_string(), (0, 0), (1, 1)),
+                ("SAMX".to_string(), (0, 3), (0, -1)),
+                ("SAMX".to_string(), (3, 0), (-1, 0)),
+                ("SAMX".to_string(), (3, 3), (-1, -1)),
+            ]
+        );
+
+        let options = SearchOptions {
+            dirs: Dirs::Diagonal,
+            ..Default::default()
+        };
+        let matches = find_words(&grid, &words, options);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].dir, (1, 1));
+        assert_eq!(matches[1].dir, (-1, -1));
+
+        let options = SearchOptions {
+            dirs: Dirs::Orthogonal,
+            ..Default::default()
+        };
+        assert_eq!(find_words(&grid, &words, options).len(), 4);
+    }
+
+    #[test]
+    fn test_find_words_without_overlaps() {
+        let grid = to_grid(["XMASAMX"]).unwrap();
+        let words = [to_word("XMAS"), to_word("SAMX")];
+        // Each of the two words is found once in each direction.
+        assert_eq!(find_words(&grid, &words, SearchOptions::default()).len(), 4);
+
+        let options = SearchOptions {
+            overlaps: false,
+            ..Default::default()
+        };
+        let matches = find_words(&grid, &words, options);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].cells().collect::<Vec<_>>(),
+            vec![(0, 0), (0, 1), (0, 2), (0, 3)]
+        );
+    }
+
+    #[test]
+    fn test_pattern_symmetries() {
+        let pattern = Pattern::parse("M.S/.A./M.S");
+        let symmetries = pattern.symmetries();
+        assert_eq!(
+            symmetries,
+            ["M.S/.A./M.S", "M.M/.A./S.S", "S.M/.A./S.M", "S.S/.A./M.M"].map(Pattern::parse)
+        );
+
+        assert_eq!(Pattern::parse("AB").symmetries().len(), 4);
+        assert_eq!(Pattern::parse("AB/CD").symmetries().len(), 8);
+        assert_eq!(Pattern::parse("A.A/.A./A.A").symmetries().len(), 1);
+    }
+
+    #[test]
+    fn test_pattern_rotate_reflect() {
+        let pattern = Pattern::parse("AB./..C");
+        assert_eq!(pattern.rotate(), Pattern::parse(".A/.B/C."));
+        assert_eq!(pattern.reflect(), Pattern::parse(".BA/C.."));
+        assert_eq!(pattern.rotate().rotate().rotate().rotate(), pattern);
+    }
+
+    #[test]
+    fn test_count_pattern() {
+        let grid = to_grid(["ABAB", "BXBA"]).unwrap();
+        assert_eq!(count_pattern(&grid, &Pattern::parse("AB")), 7);
+        // Every orthogonal neighbour of every 'A'.
+        assert_eq!(count_pattern(&grid, &Pattern::parse("A/.")), 7);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("ABABA")), 0);
+    }
+
+    #[test]
+    fn test_to_grid_ragged() {
+        assert_eq!(
+            to_grid(["ABC", "ABC", "AB", "ABC"]),
+            Err(RaggedRow {
+                line: 3,
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(
+            to_grid(["ABC", ""]).unwrap_err().to_string(),
+            "line 2: expected 3 letters, found 0"
+        );
+        assert_eq!(to_grid([]), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_tiny_grids() {
+        for rows in [vec![], vec![""], vec!["X"], vec!["XM", "AS"], vec!["MAS"]] {
+            let grid = to_grid(rows.iter().copied()).unwrap();
+            assert_eq!(count_xmas(&grid), 0);
+            assert_eq!(count_cross_mas(&grid), 0);
+        }
+        let grid = to_grid(["X"]).unwrap();
+        let words = [to_word("X"), to_word("")];
+        assert_eq!(find_words(&grid, &words, SearchOptions::default()).len(), 1);
+    }
+
+    /// A deterministic pseudo-random grid of the given letters.
+    fn random_rows(rows: usize, cols: usize, letters: &[u8], seed: u64) -> Vec<String> {
+        let mut state = seed;
+        (0..rows)
+            .map(|_| {
+                (0..cols)
+                    .map(|_| {
+                        state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                        letters[(state >> 33) as usize % letters.len()] as char
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_packed_grid_layout() {
+        let grid = PackedGrid::parse("AB\nCD\nEF").unwrap();
+        assert_eq!((grid.rows, grid.cols), (3, 2));
+        assert_eq!(grid.cells, b"\0\0\0\0AB\0CD\0EF\0\0\0\0".to_vec());
+        assert_eq!(grid.cells[grid.index(2, 1)], b'F');
+        assert_eq!(
+            PackedGrid::parse("AB\nC").err(),
+            Some(RaggedRow {
+                line: 2,
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    const EXAMPLE: &str = "\
+MMMSXXMASM
+MSAMXMSMSA
+AMXSXMAAMM
+MSAMASMSMX
+XMASAMXAMM
+XXAMMXXAMA
+SMSMSASXSS
+SAXAMASAAA
+MAMMMXMMMM
+MXMXAXMASX
+";
+
+    #[test]
+    fn test_packed_grid_count_word() {
+        let grid = PackedGrid::parse(EXAMPLE).unwrap();
+        assert_eq!(grid.count_word(b"XMAS"), 18);
+        assert_eq!(PackedGrid::parse("").unwrap().count_word(b"XMAS"), 0);
+    }
+
+    #[test]
+    fn test_packed_grid_matches_find_words() {
+        for (rows, cols) in [(1, 1), (1, 7), (7, 1), (5, 9), (23, 17)] {
+            let text = random_rows(rows, cols, b"XMAS", (rows * cols) as u64);
+            let grid = to_grid(text.iter().map(String::as_str)).unwrap();
+            let packed = PackedGrid::parse(&text.join("\n")).unwrap();
+            for word in ["XMAS", "SAS", "MM", "A", "XMASXMAS"] {
+                let expected = find_words(&grid, &[to_word(word)], SearchOptions::default()).len();
+                assert_eq!(
+                    packed.count_word(word.as_bytes()),
+                    expected,
+                    "Failed for {word} in a {rows}x{cols} grid"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_dots() {
+        let rows = ["..X...", ".SAMX.", ".A..A.", "XMAS.S", ".X...."];
+        let grid = to_grid(rows).unwrap();
+        let words = [to_word("XMAS")];
+        let matches = find_words(&grid, &words, SearchOptions::default());
+        let cells = matches
+            .iter()
+            .flat_map(WordMatch::cells)
+            .collect::<Vec<_>>();
+        assert_eq!(render(&grid, &cells, Style::Dots), rows.join("\n") + "\n");
+
+        let grid = to_grid(["MXS", "QAZ", "MXS"]).unwrap();
+        let cells = find_pattern(&grid, &Pattern::parse("M.S/.A./M.S")).concat();
+        assert_eq!(render(&grid, &cells, Style::Dots), "M.S\n.A.\nM.S\n");
+    }
+
+    #[test]
+    fn test_render_ansi() {
+        let grid = to_grid(["XMASX"]).unwrap();
+        let words = [to_word("XMAS")];
+        let matches = find_words(&grid, &words, SearchOptions::default());
+        let cells = matches
+            .iter()
+            .flat_map(WordMatch::cells)
+            .collect::<Vec<_>>();
+        let green = |ch| format!("\x1b[1;32m{ch}\x1b[0m");
+        assert_eq!(
+            render(&grid, &cells, Style::Ansi),
+            format!(
+                "{}{}{}{}X\n",
+                green('X'),
+                green('M'),
+                green('A'),
+                green('S')
+            )
+        );
+    }
+
+    fn dictionary(words: &[&str]) -> Trie<char> {
+        let mut trie = Trie::new();
+        for word in words {
+            trie.insert(word.chars());
+        }
+        trie
+    }
+
+    #[test]
+    fn test_find_paths() {
+        let grid = to_grid(["CAT", "XOD", "GXX"]).unwrap();
+        let matches = find_paths(&grid, &dictionary(&["CAT", "DOG", "COD", "TOAD", "CAG"]));
+        let mut found = matches.iter().map(|m| m.word.as_str()).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["CAT", "COD", "DOG", "TOAD"]);
+
+        let toad = matches.iter().find(|m| m.word == "TOAD").unwrap();
+        assert_eq!(toad.path, [(0, 2), (1, 1), (0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn test_find_paths_no_reuse() {
+        let grid = to_grid(["AB"]).unwrap();
+        let matches = find_paths(&grid, &dictionary(&["ABA", "AB", "BA"]));
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|m| m.word != "ABA"));
+    }
+
+    #[test]
+    fn test_find_paths_every_path() {
+        // Both As neighbour both Bs.
+        let grid = to_grid(["AB", "BA"]).unwrap();
+        let matches = find_paths(&grid, &dictionary(&["AB", "ABA"]));
+        assert_eq!(matches.iter().filter(|m| m.word == "AB").count(), 4);
+        assert_eq!(matches.iter().filter(|m| m.word == "ABA").count(), 4);
+    }
+
+    #[test]
+    fn test_find_paths_straight_words() {
+        // Straight lines are paths too.
+        let grid = to_grid(EXAMPLE.lines()).unwrap();
+        let matches = find_paths(&grid, &dictionary(&["XMAS"]));
+        assert!(matches.len() > count_xmas(&grid));
+        let straight = matches
+            .iter()
+            .filter(|m| {
+                let dir = (m.path[1].0 - m.path[0].0, m.path[1].1 - m.path[0].1);
+                m.path
+                    .windows(2)
+                    .all(|w| (w[1].0 - w[0].0, w[1].1 - w[0].1) == dir)
+            })
+            .count();
+        assert_eq!(straight, count_xmas(&grid));
+    }
+
+    #[test]
+    fn test_stream_count_word() {
+        let grid = to_grid(EXAMPLE.lines()).unwrap();
+        for word in ["XMAS", "MAS", "AM", "X", "SAMXMAS", "MMMSXXMASMX"] {
+            let words = [to_word(word)];
+            let expected = find_words(&grid, &words, SearchOptions::default()).len();
+            let streamed = stream_count_word(EXAMPLE.as_bytes(), &words[0]).unwrap();
+            assert_eq!(streamed, expected, "{word:?}");
+        }
+        assert_eq!(
+            stream_count_word(EXAMPLE.as_bytes(), &to_word("XMAS")).unwrap(),
+            18
+        );
+    }
+
+    #[test]
+    fn test_stream_count_random() {
+        for seed in 0..5 {
+            let rows = random_rows(23, 17, b"XMAS", seed);
+            let text = rows.join("\n");
+            let grid = to_grid(rows.iter().map(String::as_str)).unwrap();
+            let streamed = stream_count_word(text.as_bytes(), &to_word("XMAS")).unwrap();
+            assert_eq!(streamed, count_xmas(&grid));
+            let pattern = Pattern::parse("M.S/.A./M.S");
+            let streamed = stream_count_pattern(text.as_bytes(), &pattern).unwrap();
+            assert_eq!(streamed, count_cross_mas(&grid));
+        }
+    }
+
+    #[test]
+    fn test_stream_count_pattern() {
+        let pattern = Pattern::parse("M.S/.A./M.S");
+        assert_eq!(
+            stream_count_pattern(EXAMPLE.as_bytes(), &pattern).unwrap(),
+            9
+        );
+        // Orientations of different heights.
+        let grid = to_grid(EXAMPLE.lines()).unwrap();
+        let pattern = Pattern::parse("XM/.A");
+        let streamed = stream_count_pattern(EXAMPLE.as_bytes(), &pattern).unwrap();
+        assert_eq!(streamed, count_pattern(&grid, &pattern));
+    }
+
+    #[test]
+    fn test_stream_ragged() {
+        let err = stream_count_word("XMAS\nXMA\n".as_bytes(), &to_word("XMAS")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            stream_count_word("".as_bytes(), &to_word("XMAS")).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_nd_dirs() {
+        assert_eq!(nd_dirs(1), [[-1], [1]]);
+        let mut dirs = nd_dirs(2);
+        let mut expected = DIRS.map(|(x, y)| vec![x, y]).to_vec();
+        dirs.sort();
+        expected.sort();
+        assert_eq!(dirs, expected);
+        assert_eq!(nd_dirs(3).len(), 26);
+        assert_eq!(nd_dirs(4).len(), 80);
+    }
+
+    #[test]
+    fn test_nd_grid_matches_2d() {
+        let grid = to_grid(EXAMPLE.lines()).unwrap();
+        let nd = NdGrid {
+            shape: vec![grid.len(), width(&grid)],
+            cells: grid.concat(),
+        };
+        assert_eq!(nd.count_word(&to_word("XMAS")), count_xmas(&grid));
+        assert_eq!(nd.count_word(&to_word("X")), 19);
+        assert_eq!(nd.get(&[9, 9]), Some('X'));
+        assert_eq!(nd.get(&[10, 0]), None);
+        assert_eq!(nd.get(&[0, -1]), None);
+    }
+
+    #[test]
+    fn test_nd_grid_cube() {
+        // XMAS straight down through the layers at (0, 0), and along the
+        // space diagonal from (0, 0, 0) to (3, 3, 3).
+        let cube = "\
+XOOO
+OOOO
+OOOO
+OOOO
+
+MOOO
+OMOO
+OOOO
+OOOO
+
+AOOO
+OOOO
+OOAO
+OOOO
+
+SOOO
+OOOO
+OOOO
+OOOS
+";
+        let cube = NdGrid::parse_layers(cube).unwrap();
+        assert_eq!(cube.shape, [4, 4, 4]);
+        assert_eq!(cube.get(&[1, 1, 1]), Some('M'));
+        assert_eq!(cube.count_word(&to_word("XMAS")), 2);
+        assert_eq!(cube.count_word(&to_word("SAMX")), 2);
+        assert_eq!(cube.count_word(&to_word("MAS")), 2);
+    }
+
+    #[test]
+    fn test_nd_grid_invalid_cube() {
+        assert_eq!(
+            NdGrid::parse_layers("AB\nCD\n\nEF\n"),
+            Err(InvalidCube::RaggedLayer {
+                line: 4,
+                expected: 2,
+                found: 1,
+            })
+        );
+        assert_eq!(
+            NdGrid::parse_layers("AB\nCD\n\nEF\nG\n"),
+            Err(InvalidCube::RaggedRow(RaggedRow {
+                line: 5,
+                expected: 2,
+                found: 1,
+            }))
+        );
+        assert_eq!(NdGrid::parse_layers("").unwrap().shape, [0, 0, 0]);
     }
 }
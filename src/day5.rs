@@ -1,79 +1,734 @@
-use std::{cmp::Ordering, fs::File, io::Read};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    fs::File,
+    io::Read,
+};
 
-pub fn solve() {
+pub fn solve(args: &[String]) {
     let mut f = File::open("inputs/day5.txt").unwrap();
-    let (deps, updates) = read_input(&mut f);
-    println!("Part 1: {}", correctly_ordered(&deps, &updates));
-    println!("Part 2: {}", incorrectly_ordered(&deps, &updates));
+    let (mut rules, updates) = read_input(&mut f);
+    if let Some(i) = args.iter().position(|arg| arg == "--dot") {
+        // An optional 1-based update number follows.
+        let update = match args.get(i + 1).map(|arg| arg.parse::<usize>()) {
+            Some(Ok(n)) if (1..=updates.len()).contains(&n) => Some(&updates[n - 1][..]),
+            Some(Ok(n)) => {
+                eprintln!("No update {n}, there are {}", updates.len());
+                return;
+            }
+            _ => None,
+        };
+        print!("{}", to_dot(&rules, update));
+        return;
+    }
+    if args.iter().any(|arg| arg == "--transitive") {
+        rules = rules.transitive_closure();
+    }
+    let undetermined = find_undetermined(&rules, &updates);
+    if args.iter().any(|arg| arg == "--undetermined") {
+        for err in &undetermined {
+            println!("Undetermined: {err}");
+        }
+    }
+    if args.iter().any(|arg| arg == "--strict") {
+        if let Some(err) = undetermined.first() {
+            eprintln!("Invalid input: {err}");
+            return;
+        }
+    }
+    if args.iter().any(|arg| arg == "--explain") {
+        explain(&rules, &updates);
+    }
+    if args.iter().any(|arg| arg == "--incremental") {
+        // The rules may only be acyclic within each update, so rules that
+        // would close a cycle are skipped, and updates are checked against
+        // the rest.
+        let mut ordered = OrderedRules::default();
+        for (before, after) in rules.rules() {
+            if let Err(err) = ordered.insert(before, after) {
+                eprintln!("Skipped rule {before}|{after}: {err}");
+            }
+        }
+        let (ordered_sum, reordered_sum) = updates.iter().fold((0, 0), |(ok, fixed), update| {
+            if ordered.is_ordered(update) {
+                (ok + update[update.len() / 2], fixed)
+            } else {
+                (ok, fixed + ordered.sort(update)[update.len() / 2])
+            }
+        });
+        println!("Part 1: {ordered_sum}");
+        println!("Part 2: {reordered_sum}");
+        return;
+    }
+    if args.iter().any(|arg| arg == "--orderings") {
+        for (i, update) in updates.iter().enumerate() {
+            match count_orderings(&rules, update) {
+                Some(1) => println!("Update {}: unique order", i + 1),
+                Some(count) => println!("Update {}: {count} valid orders", i + 1),
+                None => println!("Update {}: too many pages to count orders", i + 1),
+            }
+            let mut orders = Orderings::new(&rules, update);
+            if let (Some(first), Some(second)) = (orders.next(), orders.next()) {
+                // Just a few, there may be a great many.
+                for order in [first, second].into_iter().chain(orders.take(3)) {
+                    println!("  {}", join(&order));
+                }
+            }
+        }
+    }
+    println!("Part 1: {}", correctly_ordered(&rules, &updates));
+    match incorrectly_ordered(&rules, &updates) {
+        Ok(sum) => println!("Part 2: {sum}"),
+        Err(err) => eprintln!("Invalid input: {err}"),
+    }
 }
 
-fn correctly_ordered(deps: &[(u32, u32)], updates: &[Vec<u32>]) -> u32 {
-    updates
+fn explain(rules: &RuleSet, updates: &[Vec<u32>]) {
+    for (i, update) in updates.iter().enumerate() {
+        if is_update_correctly_ordered(rules, update) {
+            continue;
+        }
+        println!("Update {}: {}", i + 1, join(update));
+        match report(rules, update) {
+            Ok(report) => {
+                for violation in &report.violations {
+                    println!("  {violation}");
+                }
+                println!("  corrected: {}", join(&report.corrected));
+                for m in &report.moves {
+                    println!("  {m}");
+                }
+            }
+            Err(err) => println!("  {err}"),
+        }
+    }
+}
+
+fn join(pages: &[u32]) -> String {
+    pages
         .iter()
-        .filter(|update| is_update_correctly_ordered(deps, update))
-        .map(|update| update[update.len() / 2])
-        .sum()
+        .map(|page| page.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A broken rule `before|after`: `after` comes first in the update.
+/// Positions are indices into the update.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Violation {
+    before: u32,
+    after: u32,
+    before_pos: usize,
+    after_pos: usize,
 }
 
-fn incorrectly_ordered(deps: &[(u32, u32)], updates: &[Vec<u32>]) -> u32 {
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rule {}|{} broken: {} at position {} comes before {} at position {}",
+            self.before, self.after, self.after, self.after_pos, self.before, self.before_pos
+        )
+    }
+}
+
+/// A page that is at a different position once the update is corrected.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Move {
+    page: u32,
+    from: usize,
+    to: usize,
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: position {} -> {}", self.page, self.from, self.to)
+    }
+}
+
+/// Why an update is out of order, and how to fix it.
+#[derive(Debug, PartialEq)]
+struct Report {
+    /// In the order of the later page's position, then the earlier's.
+    violations: Vec<Violation>,
+    corrected: Vec<u32>,
+    moves: Vec<Move>,
+}
+
+/// Reports every broken rule in the update. A correctly ordered update gets
+/// a report with no violations and no moves.
+fn report(rules: &RuleSet, update: &[u32]) -> Result<Report, Cycle> {
+    let violations = violations(rules, update);
+    let corrected = reorder(rules, update)?;
+    let moves = corrected
+        .iter()
+        .enumerate()
+        .filter_map(|(to, &page)| {
+            let from = update.iter().position(|&p| p == page).unwrap();
+            (from != to).then_some(Move { page, from, to })
+        })
+        .collect();
+    Ok(Report {
+        violations,
+        corrected,
+        moves,
+    })
+}
+
+/// The broken rules, in the order of the later page's position, then the
+/// earlier's.
+fn violations(rules: &RuleSet, update: &[u32]) -> Vec<Violation> {
+    let mut violations = vec![];
+    for j in 0..update.len() {
+        for i in 0..j {
+            if rules.must_precede(update[j], update[i]) {
+                violations.push(Violation {
+                    before: update[j],
+                    after: update[i],
+                    before_pos: j,
+                    after_pos: i,
+                });
+            }
+        }
+    }
+    violations
+}
+
+/// The rules as a Graphviz DOT graph, with an edge from each page to the
+/// pages that must come after it. Given an update, only its pages and the
+/// rules between them are included, and broken rules are drawn in red.
+fn to_dot(rules: &RuleSet, update: Option<&[u32]>) -> String {
+    let mut dot = String::from("digraph rules {\n");
+    let mut broken = HashSet::new();
+    if let Some(update) = update {
+        // Pages with no rules between them would be missing otherwise.
+        for page in update {
+            dot.push_str(&format!("    {page};\n"));
+        }
+        broken.extend(
+            violations(rules, update)
+                .iter()
+                .map(|v| (v.before, v.after)),
+        );
+    }
+    for (before, after) in rules.rules() {
+        if update.is_some_and(|pages| !pages.contains(&before) || !pages.contains(&after)) {
+            continue;
+        }
+        if broken.contains(&(before, after)) {
+            dot.push_str(&format!("    {before} -> {after} [color=red];\n"));
+        } else {
+            dot.push_str(&format!("    {before} -> {after};\n"));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Rules that contradict each other: each page must precede the next, and
+/// the last page must precede the first.
+#[derive(Debug, PartialEq)]
+struct Cycle {
+    pages: Vec<u32>,
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rules form a cycle: ")?;
+        for page in &self.pages {
+            write!(f, "{page} -> ")?;
+        }
+        write!(f, "{}", self.pages[0])
+    }
+}
+
+impl Error for Cycle {}
+
+/// Pairs of pages in an update that no rule orders. Updates are numbered
+/// from 1.
+#[derive(Debug, PartialEq)]
+struct Undetermined {
+    update: usize,
+    pairs: Vec<(u32, u32)>,
+}
+
+impl fmt::Display for Undetermined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "update {}: no rule orders ", self.update)?;
+        for (i, (page1, page2)) in self.pairs.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{page1} and {page2}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Undetermined {}
+
+/// The page ordering rules, as a graph with an edge from each page to the
+/// pages that must come after it.
+#[derive(Debug, Default, PartialEq)]
+struct RuleSet {
+    after: HashMap<u32, HashSet<u32>>,
+}
+
+impl RuleSet {
+    /// Adds the rule `before|after`.
+    fn insert(&mut self, before: u32, after: u32) {
+        self.after.entry(before).or_default().insert(after);
+    }
+
+    /// Whether there is a rule `before|after`.
+    fn must_precede(&self, before: u32, after: u32) -> bool {
+        self.after
+            .get(&before)
+            .is_some_and(|pages| pages.contains(&after))
+    }
+
+    /// Every rule, sorted.
+    fn rules(&self) -> Vec<(u32, u32)> {
+        let mut rules = self
+            .after
+            .iter()
+            .flat_map(|(&before, pages)| pages.iter().map(move |&after| (before, after)))
+            .collect::<Vec<_>>();
+        rules.sort();
+        rules
+    }
+
+    /// The pages that must come after `page`.
+    fn next_pages(&self, page: u32) -> impl Iterator<Item = u32> + '_ {
+        self.after.get(&page).into_iter().flatten().copied()
+    }
+
+    /// Adds the rules implied by chains of rules, so that a page must precede
+    /// every page reachable from it. Pages on a cycle end up having to
+    /// precede themselves, and updates with them can't be sorted.
+    fn transitive_closure(&self) -> Self {
+        let mut closure = Self::default();
+        for (&page, next) in &self.after {
+            let mut reachable = HashSet::new();
+            let mut stack = next.iter().copied().collect::<Vec<_>>();
+            while let Some(next) = stack.pop() {
+                if reachable.insert(next) {
+                    stack.extend(self.after.get(&next).into_iter().flatten());
+                }
+            }
+            closure.after.insert(page, reachable);
+        }
+        closure
+    }
+
+    /// Orders the pages of an update with a topological sort of the rules
+    /// between them, ignoring rules about other pages. Pages that are free
+    /// to go in any order keep their relative order. Fails if the rules
+    /// between the pages contradict each other.
+    fn sort(&self, update: &[u32]) -> Result<Vec<u32>, Cycle> {
+        let pages = update.iter().copied().collect::<HashSet<_>>();
+        let mut preceding = update
+            .iter()
+            .map(|&page| (page, 0))
+            .collect::<HashMap<_, _>>();
+        for &page in &pages {
+            for next in self.successors(page, &pages) {
+                *preceding.get_mut(&next).unwrap() += 1;
+            }
+        }
+
+        let mut ready = update
+            .iter()
+            .copied()
+            .filter(|page| preceding[page] == 0)
+            .collect::<VecDeque<_>>();
+        let mut sorted = Vec::with_capacity(update.len());
+        while let Some(page) = ready.pop_front() {
+            sorted.push(page);
+            // Visit successors in update order, to keep the result stable.
+            for &next in update {
+                if self.must_precede(page, next) {
+                    let count = preceding.get_mut(&next).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push_back(next);
+                    }
+                }
+            }
+        }
+        if sorted.len() < update.len() {
+            let placed = sorted.iter().copied().collect::<HashSet<_>>();
+            let unplaced = update
+                .iter()
+                .copied()
+                .filter(|page| !placed.contains(page))
+                .collect::<Vec<_>>();
+            return Err(self.cycle_among(&unplaced));
+        }
+        Ok(sorted)
+    }
+
+    /// Finds a cycle among pages that each have a predecessor among the
+    /// others, as the pages a topological sort couldn't place do.
+    fn cycle_among(&self, pages: &[u32]) -> Cycle {
+        // Walk backwards until a page repeats: the walk can't stop, and
+        // there are only so many pages.
+        let mut walk = vec![pages[0]];
+        loop {
+            let page = *walk.last().unwrap();
+            let prev = *pages
+                .iter()
+                .find(|&&prev| self.must_precede(prev, page))
+                .expect("every unplaced page has an unplaced predecessor");
+            if let Some(i) = walk.iter().position(|&p| p == prev) {
+                let mut pages = walk.split_off(i);
+                pages.reverse();
+                pages.rotate_right(1);
+                return Cycle { pages };
+            }
+            walk.push(prev);
+        }
+    }
+
+    /// The pages among `pages` that must come after `page`.
+    fn successors<'a>(
+        &'a self,
+        page: u32,
+        pages: &'a HashSet<u32>,
+    ) -> impl Iterator<Item = u32> + 'a {
+        self.next_pages(page).filter(|next| pages.contains(next))
+    }
+}
+
+impl FromIterator<(u32, u32)> for RuleSet {
+    fn from_iter<I: IntoIterator<Item = (u32, u32)>>(rules: I) -> Self {
+        let mut set = Self::default();
+        for (before, after) in rules {
+            set.insert(before, after);
+        }
+        set
+    }
+}
+
+/// Rules added one at a time, keeping a rank for every page such that each
+/// page ranks below the pages that must come after it, so updates can be
+/// sorted without a topological sort each time. Adding a rule only reorders
+/// the pages between its two pages' ranks (Pearce and Kelly's algorithm).
+/// Rules that would make a cycle are rejected, so this only holds rule sets
+/// that are acyclic as a whole.
+#[derive(Debug, Default)]
+struct OrderedRules {
+    rules: RuleSet,
+    /// The rules backwards, from each page to the pages that must come
+    /// before it.
+    reversed: RuleSet,
+    ranks: HashMap<u32, usize>,
+}
+
+impl OrderedRules {
+    /// Adds the rule `before|after`, unless it contradicts the rules so far.
+    fn insert(&mut self, before: u32, after: u32) -> Result<(), Cycle> {
+        if before == after {
+            return Err(Cycle {
+                pages: vec![before],
+            });
+        }
+        let upper = self.rank_or_insert(before);
+        let lower = self.rank_or_insert(after);
+        if lower < upper {
+            // `after` and the pages that must come after it need to move
+            // past `before` and the pages that must come before it. Only
+            // pages ranked between the two can be in the way.
+            let forward = self.reachable(after, &self.rules, |rank| rank <= upper);
+            if let Some(pages) = path(&forward, after, before) {
+                return Err(Cycle { pages });
+            }
+            let backward = self.reachable(before, &self.reversed, |rank| rank >= lower);
+            self.shift(backward.into_keys(), forward.into_keys());
+        }
+        self.rules.insert(before, after);
+        self.reversed.insert(after, before);
+        Ok(())
+    }
+
+    fn rank_or_insert(&mut self, page: u32) -> usize {
+        let next = self.ranks.len();
+        *self.ranks.entry(page).or_insert(next)
+    }
+
+    /// The pages reachable from `start` through pages whose rank is
+    /// `within` bounds, each with the page it was reached from.
+    fn reachable(
+        &self,
+        start: u32,
+        edges: &RuleSet,
+        within: impl Fn(usize) -> bool,
+    ) -> HashMap<u32, u32> {
+        let mut reached = HashMap::from([(start, start)]);
+        let mut stack = vec![start];
+        while let Some(page) = stack.pop() {
+            for next in edges.next_pages(page) {
+                if within(self.ranks[&next]) && !reached.contains_key(&next) {
+                    reached.insert(next, page);
+                    stack.push(next);
+                }
+            }
+        }
+        reached
+    }
+
+    /// Reuses the ranks of both sets of pages, giving the lowest ones to the
+    /// `backward` pages. Pages keep their order within each set.
+    fn shift(&mut self, backward: impl Iterator<Item = u32>, forward: impl Iterator<Item = u32>) {
+        let mut backward = backward.collect::<Vec<_>>();
+        let mut forward = forward.collect::<Vec<_>>();
+        backward.sort_by_key(|page| self.ranks[page]);
+        forward.sort_by_key(|page| self.ranks[page]);
+        let mut ranks = backward
+            .iter()
+            .chain(&forward)
+            .map(|page| self.ranks[page])
+            .collect::<Vec<_>>();
+        ranks.sort();
+        for (page, rank) in backward.into_iter().chain(forward).zip(ranks) {
+            self.ranks.insert(page, rank);
+        }
+    }
+
+    /// Whether no rule is broken. The ranks don't help here: they order
+    /// every pair of pages, but pages that no rule orders may go either way.
+    fn is_ordered(&self, update: &[u32]) -> bool {
+        is_update_correctly_ordered(&self.rules, update)
+    }
+
+    /// Sorts the pages by rank, keeping pages that no rule mentions where
+    /// they are relative to each other, at the end.
+    fn sort(&self, update: &[u32]) -> Vec<u32> {
+        let mut update = update.to_vec();
+        update.sort_by_key(|page| self.ranks.get(page).copied().unwrap_or(usize::MAX));
+        update
+    }
+}
+
+/// The path from `start` to `end` in a search from `start`, given the page
+/// each page was reached from.
+fn path(reached: &HashMap<u32, u32>, start: u32, end: u32) -> Option<Vec<u32>> {
+    let mut path = vec![end];
+    let mut page = *reached.get(&end)?;
+    while path.last() != Some(&start) {
+        path.push(page);
+        page = reached[&page];
+    }
+    path.reverse();
+    Some(path)
+}
+
+fn correctly_ordered(rules: &RuleSet, updates: &[Vec<u32>]) -> u32 {
     updates
         .iter()
-        .filter(|update| !is_update_correctly_ordered(deps, update))
-        .map(|update| reorder(deps, update))
+        .filter(|update| is_update_correctly_ordered(rules, update))
         .map(|update| update[update.len() / 2])
         .sum()
 }
 
-fn reorder(deps: &[(u32, u32)], update: &[u32]) -> Vec<u32> {
-    let mut update = update.to_vec();
-    update.sort_by(|num1, num2| {
-        if deps.contains(&(*num1, *num2)) {
-            Ordering::Less
-        } else {
-            Ordering::Greater
+/// Fails if any update has pages whose rules contradict each other, since
+/// they can't be put in order.
+fn incorrectly_ordered(rules: &RuleSet, updates: &[Vec<u32>]) -> Result<u32, Cycle> {
+    let mut sum = 0;
+    for update in updates {
+        if !is_update_correctly_ordered(rules, update) {
+            let sorted = reorder(rules, update)?;
+            sum += sorted[sorted.len() / 2];
         }
-    });
-    update
+    }
+    Ok(sum)
 }
 
-fn is_update_correctly_ordered(deps: &[(u32, u32)], update: &[u32]) -> bool {
-    for i in 1..update.len() {
-        if !is_pair_correctly_ordered(deps, update[i - 1], update[i]) {
-            return false;
+fn reorder(rules: &RuleSet, update: &[u32]) -> Result<Vec<u32>, Cycle> {
+    rules.sort(update)
+}
+
+/// Whether no rule is broken. Pages that no rule orders may go either way,
+/// so every pair is checked, not just neighbours.
+fn is_update_correctly_ordered(rules: &RuleSet, update: &[u32]) -> bool {
+    for i in 0..update.len() {
+        for j in i + 1..update.len() {
+            if !is_pair_correctly_ordered(rules, update[i], update[j]) {
+                return false;
+            }
         }
     }
     true
 }
 
-fn is_pair_correctly_ordered(deps: &[(u32, u32)], num1: u32, num2: u32) -> bool {
-    assert!(deps.contains(&(num1, num2)) || deps.contains(&(num2, num1)));
-    deps.contains(&(num1, num2))
+fn is_pair_correctly_ordered(rules: &RuleSet, num1: u32, num2: u32) -> bool {
+    !rules.must_precede(num2, num1)
+}
+
+/// The pairs of pages, in update order, that no rule orders either way.
+fn undetermined_pairs(rules: &RuleSet, update: &[u32]) -> Vec<(u32, u32)> {
+    let mut pairs = vec![];
+    for i in 0..update.len() {
+        for j in i + 1..update.len() {
+            let (num1, num2) = (update[i], update[j]);
+            if !rules.must_precede(num1, num2) && !rules.must_precede(num2, num1) {
+                pairs.push((num1, num2));
+            }
+        }
+    }
+    pairs
+}
+
+fn find_undetermined(rules: &RuleSet, updates: &[Vec<u32>]) -> Vec<Undetermined> {
+    updates
+        .iter()
+        .enumerate()
+        .map(|(i, update)| Undetermined {
+            update: i + 1,
+            pairs: undetermined_pairs(rules, update),
+        })
+        .filter(|err| !err.pairs.is_empty())
+        .collect()
+}
+
+/// Updates with more pages than this aren't counted by `count_orderings`:
+/// it needs `2^n` counters, and `21!` doesn't fit in a `u64`.
+const MAX_COUNTED_PAGES: usize = 20;
+
+/// For each page of the update, the positions of the pages that must come
+/// before it.
+fn predecessors(rules: &RuleSet, update: &[u32]) -> Vec<Vec<usize>> {
+    update
+        .iter()
+        .map(|&page| {
+            (0..update.len())
+                .filter(|&i| rules.must_precede(update[i], page))
+                .collect()
+        })
+        .collect()
+}
+
+/// Counts the orders of the update's pages that break no rule, so 1 means
+/// the rules fix the order, and 0 that they contradict each other. `None`
+/// if the update has more than `MAX_COUNTED_PAGES` pages.
+fn count_orderings(rules: &RuleSet, update: &[u32]) -> Option<u64> {
+    if update.len() > MAX_COUNTED_PAGES {
+        return None;
+    }
+    let required = predecessors(rules, update)
+        .into_iter()
+        .map(|preds| preds.into_iter().fold(0, |mask, i| mask | 1 << i))
+        .collect::<Vec<usize>>();
+
+    // `counts[placed]` is the number of valid ways to put the pages in the
+    // set `placed` first.
+    let mut counts = vec![0u64; 1 << update.len()];
+    counts[0] = 1;
+    for placed in 0..counts.len() {
+        if counts[placed] == 0 {
+            continue;
+        }
+        for (i, &required) in required.iter().enumerate() {
+            if placed & 1 << i == 0 && placed & required == required {
+                counts[placed | 1 << i] += counts[placed];
+            }
+        }
+    }
+    counts.last().copied()
+}
+
+/// The valid orders of an update's pages, generated one at a time.
+struct Orderings<'a> {
+    update: &'a [u32],
+    predecessors: Vec<Vec<usize>>,
+    placed: Vec<bool>,
+    /// The positions in the update of the pages placed so far.
+    order: Vec<usize>,
+    /// The next position to try placing after `order`.
+    next: usize,
+    done: bool,
+}
+
+impl<'a> Orderings<'a> {
+    fn new(rules: &RuleSet, update: &'a [u32]) -> Self {
+        Self {
+            update,
+            predecessors: predecessors(rules, update),
+            placed: vec![false; update.len()],
+            order: Vec::with_capacity(update.len()),
+            next: 0,
+            // With a cycle, the search would try every order of the other
+            // pages before giving up.
+            done: rules.sort(update).is_err(),
+        }
+    }
+
+    fn can_place(&self, i: usize) -> bool {
+        !self.placed[i] && self.predecessors[i].iter().all(|&j| self.placed[j])
+    }
+
+    /// Takes back the last page placed, to try the ones after it instead.
+    /// Returns false when there's nothing left to take back.
+    fn backtrack(&mut self) -> bool {
+        let Some(i) = self.order.pop() else {
+            return false;
+        };
+        self.placed[i] = false;
+        self.next = i + 1;
+        true
+    }
+}
+
+impl Iterator for Orderings<'_> {
+    type Item = Vec<u32>;
+
+    /// Orders come in lexicographic order of the pages' positions in the
+    /// update, so the update itself comes first if it's valid.
+    fn next(&mut self) -> Option<Vec<u32>> {
+        while !self.done {
+            if self.order.len() == self.update.len() {
+                let order = self.order.iter().map(|&i| self.update[i]).collect();
+                self.done = !self.backtrack();
+                return Some(order);
+            }
+            match (self.next..self.update.len()).find(|&i| self.can_place(i)) {
+                Some(i) => {
+                    self.placed[i] = true;
+                    self.order.push(i);
+                    self.next = 0;
+                }
+                None => self.done = !self.backtrack(),
+            }
+        }
+        None
+    }
 }
 
-fn read_input<R: Read>(reader: &mut R) -> (Vec<(u32, u32)>, Vec<Vec<u32>>) {
+fn read_input<R: Read>(reader: &mut R) -> (RuleSet, Vec<Vec<u32>>) {
     let mut text = String::new();
     reader.read_to_string(&mut text).unwrap();
 
     let mut lines = text.lines();
 
-    let mut deps = vec![];
+    let mut rules = RuleSet::default();
     let mut updates = vec![];
 
-    while let Some(line) = lines.next() {
+    for line in lines.by_ref() {
         if line.is_empty() {
             break;
         }
         let mut pair = line.split('|').map(|s| s.parse().unwrap());
         let num1 = pair.next().unwrap();
         let num2 = pair.next().unwrap();
-        deps.push((num1, num2));
+        rules.insert(num1, num2);
     }
 
     for line in lines {
         let nums = line.split(',').map(|s| s.parse().unwrap()).collect();
         updates.push(nums);
     }
-    (deps, updates)
+    (rules, updates)
 }
 
 #[cfg(test)]
@@ -113,20 +768,344 @@ mod tests {
     #[test]
     fn test_read_input() {
         let text = "1|2\n3|1\n3|2\n\n1,2,3\n2,1,3\n3,1,2";
-        let (deps, updates) = read_input(&mut Cursor::new(text));
-        assert_eq!(deps, vec![(1, 2), (3, 1), (3, 2)]);
+        let (rules, updates) = read_input(&mut Cursor::new(text));
+        assert_eq!(rules, RuleSet::from_iter([(1, 2), (3, 1), (3, 2)]));
         assert_eq!(updates, vec![vec![1, 2, 3], vec![2, 1, 3], vec![3, 1, 2]]);
     }
 
     #[test]
     fn test_correctly_ordered() {
-        let (deps, updates) = read_input(&mut Cursor::new(TEST_TEXT));
-        assert_eq!(correctly_ordered(&deps, &updates), 143);
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        assert_eq!(correctly_ordered(&rules, &updates), 143);
     }
 
     #[test]
     fn test_incorrectly_ordered() {
-        let (deps, updates) = read_input(&mut Cursor::new(TEST_TEXT));
-        assert_eq!(incorrectly_ordered(&deps, &updates), 123);
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        assert_eq!(incorrectly_ordered(&rules, &updates), Ok(123));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let (rules, _) = read_input(&mut Cursor::new(TEST_TEXT));
+        assert_eq!(
+            reorder(&rules, &[75, 97, 47, 61, 53]).unwrap(),
+            [97, 75, 47, 61, 53]
+        );
+        assert_eq!(reorder(&rules, &[61, 13, 29]).unwrap(), [61, 29, 13]);
+        assert_eq!(
+            reorder(&rules, &[97, 13, 75, 29, 47]).unwrap(),
+            [97, 75, 47, 29, 13]
+        );
+    }
+
+    #[test]
+    fn test_sort_ignores_other_pages() {
+        // 1 must precede 3 only through 2, which isn't in the update.
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (4, 1)]);
+        assert_eq!(rules.sort(&[3, 1]).unwrap(), [3, 1]);
+        assert_eq!(rules.sort(&[3, 2, 1, 4]).unwrap(), [4, 1, 2, 3]);
+        assert!(rules.must_precede(1, 2));
+        assert!(!rules.must_precede(2, 1));
+        assert!(!rules.must_precede(1, 3));
+    }
+
+    #[test]
+    fn test_cycle() {
+        // 1 -> 2 -> 3 -> 1, with 4 before all of them and 5 after.
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (3, 1), (4, 1), (3, 5)]);
+        let cycle = rules.sort(&[5, 3, 4, 2, 1]).unwrap_err();
+        assert_eq!(cycle.pages.len(), 3);
+        for (i, &page) in cycle.pages.iter().enumerate() {
+            let next = cycle.pages[(i + 1) % cycle.pages.len()];
+            assert!(rules.must_precede(page, next));
+        }
+        assert_eq!(
+            RuleSet::from_iter([(1, 2), (2, 1)])
+                .sort(&[1, 2])
+                .unwrap_err()
+                .to_string(),
+            "rules form a cycle: 1 -> 2 -> 1"
+        );
+
+        // The cycle only matters when the update has all its pages.
+        assert!(rules.sort(&[1, 2, 4, 5]).is_ok());
+        assert_eq!(rules.sort(&[5, 2, 4, 1]).unwrap(), [5, 4, 1, 2]);
+    }
+
+    #[test]
+    fn test_incorrectly_ordered_cycle() {
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (3, 1)]);
+        let updates = [vec![1, 2], vec![3, 1, 2]];
+        assert!(incorrectly_ordered(&rules, &updates).is_err());
+        assert_eq!(incorrectly_ordered(&rules, &updates[..1]), Ok(0));
+    }
+
+    #[test]
+    fn test_undetermined_pairs() {
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        assert!(find_undetermined(&rules, &updates).is_empty());
+
+        let rules = RuleSet::from_iter([(1, 2), (2, 3)]);
+        assert_eq!(undetermined_pairs(&rules, &[1, 2, 3]), [(1, 3)]);
+        let updates = [vec![1, 2], vec![3, 4, 1]];
+        let undetermined = find_undetermined(&rules, &updates);
+        assert_eq!(undetermined.len(), 1);
+        assert_eq!(
+            undetermined[0].to_string(),
+            "update 2: no rule orders 3 and 4, 3 and 1, 4 and 1"
+        );
+    }
+
+    #[test]
+    fn test_unconstrained_any_order() {
+        // No rule between 1 and 3, or about 4, so any order of them is fine.
+        let rules = RuleSet::from_iter([(1, 2), (2, 3)]);
+        assert!(is_update_correctly_ordered(&rules, &[3, 1]));
+        assert!(is_update_correctly_ordered(&rules, &[1, 4, 2]));
+        assert!(!is_update_correctly_ordered(&rules, &[2, 4, 1]));
+        assert_eq!(correctly_ordered(&rules, &[vec![4, 1, 2]]), 1);
+        assert_eq!(incorrectly_ordered(&rules, &[vec![2, 4, 1]]), Ok(1));
+    }
+
+    #[test]
+    fn test_transitive_closure() {
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (3, 4)]).transitive_closure();
+        assert!(rules.must_precede(1, 4));
+        assert!(rules.must_precede(2, 4));
+        assert!(!rules.must_precede(4, 1));
+        assert!(!is_update_correctly_ordered(&rules, &[4, 1]));
+        assert!(undetermined_pairs(&rules, &[4, 2, 1]).is_empty());
+        assert_eq!(rules.sort(&[4, 2, 1]).unwrap(), [1, 2, 4]);
+
+        // The example rules are already transitive.
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        let closure = rules.transitive_closure();
+        assert_eq!(closure, rules);
+        assert_eq!(incorrectly_ordered(&closure, &updates), Ok(123));
+    }
+
+    #[test]
+    fn test_transitive_closure_cycle() {
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (3, 1)]).transitive_closure();
+        assert!(rules.must_precede(1, 1));
+        // Without the closure, 1 and 2 alone could be sorted.
+        assert!(rules.sort(&[2, 1]).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let (rules, _) = read_input(&mut Cursor::new(TEST_TEXT));
+        let report = report(&rules, &[97, 13, 75, 29, 47]).unwrap();
+        assert_eq!(report.corrected, [97, 75, 47, 29, 13]);
+        assert_eq!(
+            report.violations,
+            [
+                (75, 13, 2, 1),
+                (29, 13, 3, 1),
+                (47, 13, 4, 1),
+                (47, 29, 4, 3)
+            ]
+            .map(|(before, after, before_pos, after_pos)| Violation {
+                before,
+                after,
+                before_pos,
+                after_pos,
+            })
+        );
+        assert_eq!(
+            report.moves,
+            [(75, 2, 1), (47, 4, 2), (13, 1, 4)].map(|(page, from, to)| Move { page, from, to })
+        );
+        assert_eq!(
+            report.violations[3].to_string(),
+            "rule 47|29 broken: 29 at position 3 comes before 47 at position 4"
+        );
+        assert_eq!(report.moves[0].to_string(), "75: position 2 -> 1");
+    }
+
+    #[test]
+    fn test_report_correct() {
+        let (rules, _) = read_input(&mut Cursor::new(TEST_TEXT));
+        let report = report(&rules, &[75, 47, 61, 53, 29]).unwrap();
+        assert!(report.violations.is_empty());
+        assert!(report.moves.is_empty());
+        assert_eq!(report.corrected, [75, 47, 61, 53, 29]);
+    }
+
+    #[test]
+    fn test_count_orderings() {
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        for update in &updates {
+            assert_eq!(count_orderings(&rules, update), Some(1));
+        }
+
+        let rules = RuleSet::from_iter([(1, 2), (1, 3), (2, 4), (3, 4)]);
+        assert_eq!(count_orderings(&rules, &[4, 3, 2, 1]), Some(2));
+        assert_eq!(count_orderings(&rules, &[4, 3, 2, 1, 5]), Some(10));
+        assert_eq!(count_orderings(&rules, &[]), Some(1));
+        assert_eq!(
+            count_orderings(&RuleSet::default(), &[1, 2, 3, 4]),
+            Some(24)
+        );
+
+        let cyclic = RuleSet::from_iter([(1, 2), (2, 1)]);
+        assert_eq!(count_orderings(&cyclic, &[1, 2, 3]), Some(0));
+
+        let update = (0..20).collect::<Vec<_>>();
+        assert_eq!(
+            count_orderings(&RuleSet::default(), &update),
+            Some((1..=20).product())
+        );
+        let update = (0..21).collect::<Vec<_>>();
+        assert_eq!(count_orderings(&RuleSet::default(), &update), None);
+    }
+
+    #[test]
+    fn test_orderings() {
+        let rules = RuleSet::from_iter([(1, 2), (1, 3), (2, 4), (3, 4)]);
+        let orders = Orderings::new(&rules, &[4, 3, 2, 1]).collect::<Vec<_>>();
+        assert_eq!(orders, [[1, 3, 2, 4], [1, 2, 3, 4]]);
+
+        let orders = Orderings::new(&RuleSet::default(), &[1, 2, 3]).collect::<Vec<_>>();
+        assert_eq!(
+            orders,
+            [
+                [1, 2, 3],
+                [1, 3, 2],
+                [2, 1, 3],
+                [2, 3, 1],
+                [3, 1, 2],
+                [3, 2, 1]
+            ]
+        );
+
+        let cyclic = RuleSet::from_iter([(1, 2), (2, 1)]);
+        assert_eq!(Orderings::new(&cyclic, &[1, 2, 3]).next(), None);
+        // Fails right away rather than after trying all 18! orders of the
+        // free pages.
+        let update = (3..=20).chain([1, 2]).collect::<Vec<_>>();
+        assert_eq!(Orderings::new(&cyclic, &update).next(), None);
+        assert_eq!(Orderings::new(&rules, &[]).collect::<Vec<_>>(), [vec![]]);
+    }
+
+    #[test]
+    fn test_orderings_match_count() {
+        let rules = RuleSet::from_iter([(1, 2), (3, 4), (5, 4), (2, 6)]);
+        let update = [6, 5, 4, 3, 2, 1];
+        let count = Orderings::new(&rules, &update)
+            .inspect(|order| assert!(is_update_correctly_ordered(&rules, order)))
+            .count();
+        assert_eq!(Some(count as u64), count_orderings(&rules, &update));
+    }
+
+    #[test]
+    fn test_ordered_rules() {
+        let (rules, updates) = read_input(&mut Cursor::new(TEST_TEXT));
+        let mut ordered = OrderedRules::default();
+        // Backwards, so that most rules force pages to move.
+        for (before, after) in rules.rules().into_iter().rev() {
+            ordered.insert(before, after).unwrap();
+        }
+        for (before, after) in rules.rules() {
+            assert!(ordered.ranks[&before] < ordered.ranks[&after]);
+        }
+        for update in &updates {
+            assert_eq!(
+                ordered.is_ordered(update),
+                is_update_correctly_ordered(&rules, update)
+            );
+            assert_eq!(ordered.sort(update), reorder(&rules, update).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_ordered_rules_cycle() {
+        let mut ordered = OrderedRules::default();
+        ordered.insert(1, 2).unwrap();
+        ordered.insert(2, 3).unwrap();
+        ordered.insert(4, 1).unwrap();
+        assert_eq!(
+            ordered.insert(3, 1),
+            Err(Cycle {
+                pages: vec![1, 2, 3]
+            })
+        );
+        assert_eq!(ordered.insert(2, 2), Err(Cycle { pages: vec![2] }));
+        // Rejected rules leave the order as it was.
+        assert!(ordered.is_ordered(&[4, 1, 2, 3]));
+        assert!(!ordered.rules.must_precede(3, 1));
+        ordered.insert(3, 5).unwrap();
+        ordered.insert(5, 4).unwrap_err();
+        assert_eq!(ordered.sort(&[5, 3, 9, 1, 4]), [4, 1, 3, 5, 9]);
+    }
+
+    #[test]
+    fn test_ordered_rules_moves_both_sides() {
+        // 3 and what precedes it must move before 1 and what follows it.
+        let mut ordered = OrderedRules::default();
+        for (before, after) in [(1, 2), (2, 5), (6, 3), (3, 4), (4, 7), (8, 6)] {
+            ordered.insert(before, after).unwrap();
+        }
+        ordered.insert(3, 1).unwrap();
+        assert!(ordered.is_ordered(&[8, 6, 3, 1, 2, 5]));
+        assert!(ordered.is_ordered(&[3, 4, 7]));
+        assert!(!ordered.is_ordered(&[1, 3]));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let rules = RuleSet::from_iter([(1, 2), (2, 3), (1, 3), (4, 1)]);
+        assert_eq!(
+            to_dot(&rules, None),
+            "digraph rules {
+    1 -> 2;
+    1 -> 3;
+    2 -> 3;
+    4 -> 1;
+}
+"
+        );
+        assert_eq!(
+            to_dot(&rules, Some(&[3, 1, 2, 5])),
+            "digraph rules {
+    3;
+    1;
+    2;
+    5;
+    1 -> 2;
+    1 -> 3 [color=red];
+    2 -> 3 [color=red];
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_ordered_rules_unconstrained() {
+        // Incomplete, then cyclic as a whole but not within any update
+        // without all of 1, 2 and 3.
+        for rules in [vec![(1, 2), (3, 4)], vec![(1, 2), (2, 3), (3, 1)]] {
+            let mut ordered = OrderedRules::default();
+            let mut accepted = RuleSet::default();
+            for (before, after) in rules {
+                if ordered.insert(before, after).is_ok() {
+                    accepted.insert(before, after);
+                }
+            }
+            for update in [[3, 1], [1, 3], [2, 1], [4, 1], [3, 2], [4, 3]] {
+                assert_eq!(
+                    ordered.is_ordered(&update),
+                    is_update_correctly_ordered(&accepted, &update),
+                    "{update:?}"
+                );
+            }
+        }
+        let mut ordered = OrderedRules::default();
+        ordered.insert(1, 2).unwrap();
+        ordered.insert(3, 4).unwrap();
+        assert!(ordered.is_ordered(&[3, 1]));
+        assert!(ordered.is_ordered(&[3, 1, 4, 2]));
+        assert!(!ordered.is_ordered(&[2, 3, 1]));
     }
 }
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, fs::File, io::Read};
 
-pub fn solve() {
+pub fn solve(_args: &[String]) {
     let mut f = File::open("inputs/day6.txt").unwrap();
     let (map, guard) = read_input(&mut f);
 
@@ -4,3 +4,5 @@ pub mod day3;
 pub mod day4;
 pub mod day5;
 pub mod day6;
+pub mod regex;
+pub mod trie;
@@ -14,5 +14,6 @@ fn main() {
     let mut args = args();
     args.next();
     let day = args.next().unwrap().parse::<usize>().unwrap();
-    days[day - 1]();
+    let flags = args.collect::<Vec<_>>();
+    days[day - 1](&flags);
 }
@@ -0,0 +1,683 @@
+//! A small regular expression engine over bytes.
+//!
+//! Patterns are compiled to a Thompson NFA and run with a Pike VM, so matching
+//! takes time linear in the haystack regardless of the pattern. Matches are
+//! leftmost-first, like Perl: of the matches starting at the leftmost position,
+//! the one preferred by the alternation order and greediness wins.
+//!
+//! Supported syntax:
+//!
+//! - Literals, and `\` followed by punctuation for a literal metacharacter.
+//! - `.` (any byte except `\n`), `\d`, `\w`, `\s` and their negations `\D`,
+//!   `\W`, `\S`, and classes like `[a-z_]` or `[^,)]`.
+//! - Alternation `a|b`, capturing groups `(...)` and non-capturing groups
+//!   `(?:...)`.
+//! - Repetition `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}`, optionally followed
+//!   by `?` to make it lazy.
+
+use std::{error, fmt, ops::Range};
+
+/// Counted repetitions are expanded when compiling, so their bounds are capped.
+const MAX_REPEAT: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    UnexpectedEnd,
+    UnexpectedChar {
+        pos: usize,
+        ch: char,
+    },
+    /// A quantifier with nothing to repeat, or with invalid bounds.
+    InvalidRepeat {
+        pos: usize,
+    },
+    /// A malformed or non-ASCII character class.
+    InvalidClass {
+        pos: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEnd => write!(f, "unexpected end of pattern"),
+            Error::UnexpectedChar { pos, ch } => {
+                write!(f, "unexpected {ch:?} at position {pos}")
+            }
+            Error::InvalidRepeat { pos } => write!(f, "invalid repetition at position {pos}"),
+            Error::InvalidClass { pos } => write!(f, "invalid class at position {pos}"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Inclusive byte ranges, sorted and non-overlapping.
+type Class = Vec<(u8, u8)>;
+
+#[derive(Debug)]
+enum Node {
+    Class(Class),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Group(Box<Node>, Option<usize>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Debug)]
+enum Inst {
+    Class(Class),
+    /// Try both branches, preferring the first.
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+#[derive(Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    /// Number of groups, including the implicit group 0 for the whole match.
+    groups: usize,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 1,
+        };
+        let node = parser.parse_alt()?;
+        if let Some(&ch) = parser.chars.get(parser.pos) {
+            return Err(Error::UnexpectedChar {
+                pos: parser.pos,
+                ch,
+            });
+        }
+
+        let mut prog = vec![Inst::Save(0)];
+        compile(&node, &mut prog);
+        prog.push(Inst::Save(1));
+        prog.push(Inst::Match);
+        Ok(Self {
+            prog,
+            groups: parser.groups,
+        })
+    }
+
+    /// Number of groups, including group 0 for the whole match.
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    /// The leftmost match starting at or after `start`.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<Captures> {
+        let mut visited = vec![usize::MAX; self.prog.len()];
+        let mut clist = vec![];
+        let mut nlist = vec![];
+        let mut matched = None;
+        for pos in start..=haystack.len() {
+            if matched.is_none() {
+                let slots = vec![None; 2 * self.groups];
+                self.add_thread(&mut clist, &mut visited, 0, pos, slots);
+            }
+            if clist.is_empty() {
+                break;
+            }
+            for thread in clist.drain(..) {
+                match &self.prog[thread.pc] {
+                    Inst::Class(class) => {
+                        if haystack.get(pos).is_some_and(|b| class_contains(class, *b)) {
+                            self.add_thread(
+                                &mut nlist,
+                                &mut visited,
+                                thread.pc + 1,
+                                pos + 1,
+                                thread.slots,
+                            );
+                        }
+                    }
+                    Inst::Match => {
+                        matched = Some(thread.slots);
+                        // Lower priority threads can't win anymore.
+                        break;
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            std::mem::swap(&mut clist, &mut nlist);
+            nlist.clear();
+        }
+        matched.map(|slots| Captures { slots })
+    }
+
+    /// Follows jumps, splits and saves from `pc`, adding the threads that
+    /// are waiting on input (or have matched) to `list`, in priority order.
+    fn add_thread(
+        &self,
+        list: &mut Vec<Thread>,
+        visited: &mut [usize],
+        pc: usize,
+        pos: usize,
+        mut slots: Vec<Option<usize>>,
+    ) {
+        if visited[pc] == pos {
+            return;
+        }
+        visited[pc] = pos;
+        match self.prog[pc] {
+            Inst::Jmp(to) => self.add_thread(list, visited, to, pos, slots),
+            Inst::Split(x, y) => {
+                self.add_thread(list, visited, x, pos, slots.clone());
+                self.add_thread(list, visited, y, pos, slots);
+            }
+            Inst::Save(slot) => {
+                slots[slot] = Some(pos);
+                self.add_thread(list, visited, pc + 1, pos, slots);
+            }
+            Inst::Class(_) | Inst::Match => list.push(Thread { pc, slots }),
+        }
+    }
+
+    /// All non-overlapping matches, left to right.
+    pub fn find_iter<'r, 'h>(&'r self, haystack: &'h [u8]) -> FindIter<'r, 'h> {
+        FindIter {
+            regex: self,
+            haystack,
+            pos: Some(0),
+        }
+    }
+}
+
+struct Thread {
+    pc: usize,
+    slots: Vec<Option<usize>>,
+}
+
+/// The spans of the groups of a match.
+#[derive(Debug, PartialEq)]
+pub struct Captures {
+    slots: Vec<Option<usize>>,
+}
+
+impl Captures {
+    /// The span of group `i`, or `None` if it didn't participate in the match.
+    pub fn get(&self, i: usize) -> Option<Range<usize>> {
+        match (self.slots.get(2 * i)?, self.slots.get(2 * i + 1)?) {
+            (Some(start), Some(end)) => Some(*start..*end),
+            _ => None,
+        }
+    }
+
+    /// The span of the whole match.
+    pub fn span(&self) -> Range<usize> {
+        self.get(0).unwrap()
+    }
+}
+
+pub struct FindIter<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h [u8],
+    /// Where to search next, or `None` once done.
+    pos: Option<usize>,
+}
+
+impl Iterator for FindIter<'_, '_> {
+    type Item = Captures;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos?;
+        let Some(captures) = self.regex.find_at(self.haystack, pos) else {
+            self.pos = None;
+            return None;
+        };
+        let span = captures.span();
+        // Step over empty matches, so that they aren't found again.
+        self.pos = if span.is_empty() {
+            Some(span.end + 1).filter(|&pos| pos <= self.haystack.len())
+        } else {
+            Some(span.end)
+        };
+        Some(captures)
+    }
+}
+
+/// Escapes the metacharacters in `text`, so that it matches literally.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        if "\\.+*?()|[]{}^$-".contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+fn class_contains(class: &Class, b: u8) -> bool {
+    class.iter().any(|&(lo, hi)| lo <= b && b <= hi)
+}
+
+fn normalize(mut class: Class) -> Class {
+    class.sort();
+    let mut merged: Class = vec![];
+    for (lo, hi) in class {
+        match merged.last_mut() {
+            Some((_, last_hi)) if lo as usize <= *last_hi as usize + 1 => {
+                *last_hi = (*last_hi).max(hi);
+            }
+            _ => merged.push((lo, hi)),
+        }
+    }
+    merged
+}
+
+fn negate(class: &[(u8, u8)]) -> Class {
+    let mut negated = vec![];
+    let mut next = 0;
+    for &(lo, hi) in class {
+        if lo as usize > next {
+            negated.push((next as u8, lo - 1));
+        }
+        next = hi as usize + 1;
+    }
+    if next <= u8::MAX as usize {
+        negated.push((next as u8, u8::MAX));
+    }
+    negated
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Number of groups seen so far, including group 0.
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char, Error> {
+        let ch = self.peek().ok_or(Error::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(ch)
+    }
+
+    fn consume_if(&mut self, ch: char) -> bool {
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_alt(&mut self) -> Result<Node, Error> {
+        let mut alternatives = vec![self.parse_concat()?];
+        while self.consume_if('|') {
+            alternatives.push(self.parse_concat()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Node::Alt(alternatives)
+        })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = vec![];
+        while let Some(ch) = self.peek() {
+            if ch == '|' || ch == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_repeat(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn parse_repeat(&mut self, node: Node) -> Result<Node, Error> {
+        let pos = self.pos;
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.parse_number().ok_or(Error::InvalidRepeat { pos })?;
+                let max = if self.consume_if(',') {
+                    self.parse_number()
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') {
+                    return Err(Error::InvalidRepeat { pos });
+                }
+                if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                    return Err(Error::InvalidRepeat { pos });
+                }
+                (min, max)
+            }
+            _ => return Ok(node),
+        };
+        self.pos += 1;
+        let greedy = !self.consume_if('?');
+        if matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+            return Err(Error::InvalidRepeat { pos: self.pos });
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn parse_number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits.parse().ok()
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, Error> {
+        let pos = self.pos;
+        match self.next()? {
+            '(' => {
+                let index = if self.consume_if('?') {
+                    if !self.consume_if(':') {
+                        return Err(Error::UnexpectedChar {
+                            pos: pos + 1,
+                            ch: '?',
+                        });
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups - 1)
+                };
+                let node = self.parse_alt()?;
+                if !self.consume_if(')') {
+                    return Err(Error::UnexpectedEnd);
+                }
+                Ok(Node::Group(Box::new(node), index))
+            }
+            '[' => self.parse_class().map(Node::Class),
+            '.' => Ok(Node::Class(negate(&[(b'\n', b'\n')]))),
+            '\\' => self.parse_escape().map(Node::Class),
+            ch @ ('*' | '+' | '?' | '{') => Err(Error::UnexpectedChar { pos, ch }),
+            ch => Ok(literal(ch)),
+        }
+    }
+
+    /// Parses what follows a `\`.
+    fn parse_escape(&mut self) -> Result<Class, Error> {
+        let pos = self.pos;
+        let ch = self.next()?;
+        let shorthand = match ch.to_ascii_lowercase() {
+            'd' => Some(vec![(b'0', b'9')]),
+            'w' => Some(vec![(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')]),
+            's' => Some(vec![(b'\t', b'\r'), (b' ', b' ')]),
+            _ => None,
+        };
+        if let Some(class) = shorthand {
+            return Ok(if ch.is_ascii_uppercase() {
+                negate(&class)
+            } else {
+                class
+            });
+        }
+        let b = match ch {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            ch if ch.is_ascii_punctuation() => ch as u8,
+            ch => return Err(Error::UnexpectedChar { pos, ch }),
+        };
+        Ok(vec![(b, b)])
+    }
+
+    /// Parses a class, after the opening `[`.
+    fn parse_class(&mut self) -> Result<Class, Error> {
+        let start = self.pos - 1;
+        let negated = self.consume_if('^');
+        let mut class = vec![];
+        let mut first = true;
+        loop {
+            let pos = self.pos;
+            let ch = self.next()?;
+            if ch == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match ch {
+                '\\' => {
+                    let escaped = self.parse_escape()?;
+                    match escaped[..] {
+                        [(lo, hi)] if lo == hi => lo,
+                        _ => {
+                            class.extend(escaped);
+                            continue;
+                        }
+                    }
+                }
+                ch if ch.is_ascii() => ch as u8,
+                _ => return Err(Error::InvalidClass { pos }),
+            };
+            // A `-` at either end of the class is a literal.
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let hi = match self.next()? {
+                    '\\' => match self.parse_escape()?[..] {
+                        [(lo, hi)] if lo == hi => lo,
+                        _ => return Err(Error::InvalidClass { pos }),
+                    },
+                    ch if ch.is_ascii() => ch as u8,
+                    _ => return Err(Error::InvalidClass { pos }),
+                };
+                if hi < lo {
+                    return Err(Error::InvalidClass { pos });
+                }
+                class.push((lo, hi));
+            } else {
+                class.push((lo, lo));
+            }
+        }
+        if class.is_empty() {
+            return Err(Error::InvalidClass { pos: start });
+        }
+        let class = normalize(class);
+        Ok(if negated { negate(&class) } else { class })
+    }
+}
+
+/// A character as a sequence of bytes.
+fn literal(ch: char) -> Node {
+    let mut buf = [0; 4];
+    let bytes = ch.encode_utf8(&mut buf).as_bytes();
+    if let [b] = bytes {
+        Node::Class(vec![(*b, *b)])
+    } else {
+        Node::Concat(bytes.iter().map(|&b| Node::Class(vec![(b, b)])).collect())
+    }
+}
+
+fn compile(node: &Node, prog: &mut Vec<Inst>) {
+    match node {
+        Node::Class(class) => prog.push(Inst::Class(class.clone())),
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, prog)),
+        Node::Alt(alternatives) => {
+            let mut jumps = vec![];
+            let (last, rest) = alternatives.split_last().unwrap();
+            for alternative in rest {
+                let split = prog.len();
+                prog.push(Inst::Split(split + 1, 0));
+                compile(alternative, prog);
+                jumps.push(prog.len());
+                prog.push(Inst::Jmp(0));
+                prog[split] = Inst::Split(split + 1, prog.len());
+            }
+            compile(last, prog);
+            let end = prog.len();
+            for jump in jumps {
+                prog[jump] = Inst::Jmp(end);
+            }
+        }
+        Node::Group(node, None) => compile(node, prog),
+        Node::Group(node, Some(index)) => {
+            prog.push(Inst::Save(2 * index));
+            compile(node, prog);
+            prog.push(Inst::Save(2 * index + 1));
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => {
+            let split = |body: usize, exit: usize| {
+                if *greedy {
+                    Inst::Split(body, exit)
+                } else {
+                    Inst::Split(exit, body)
+                }
+            };
+            for _ in 0..*min {
+                compile(node, prog);
+            }
+            match max {
+                Some(max) => {
+                    // Each optional copy can skip straight to the end.
+                    let mut splits = vec![];
+                    for _ in *min..*max {
+                        splits.push(prog.len());
+                        prog.push(Inst::Jmp(0));
+                        compile(node, prog);
+                    }
+                    let end = prog.len();
+                    for pc in splits {
+                        prog[pc] = split(pc + 1, end);
+                    }
+                }
+                None => {
+                    let start = prog.len();
+                    prog.push(Inst::Jmp(0));
+                    compile(node, prog);
+                    prog.push(Inst::Jmp(start));
+                    prog[start] = split(start + 1, prog.len());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find_all<'h>(pattern: &str, haystack: &'h str) -> Vec<&'h str> {
+        let regex = Regex::new(pattern).unwrap();
+        regex
+            .find_iter(haystack.as_bytes())
+            .map(|captures| &haystack[captures.span()])
+            .collect()
+    }
+
+    #[test]
+    fn test_literals_and_classes() {
+        assert_eq!(find_all("ab", "abcab"), vec!["ab", "ab"]);
+        assert_eq!(find_all(r"\d+", "a12b3"), vec!["12", "3"]);
+        assert_eq!(find_all(r"[a-c_]+", "xab_c-d"), vec!["ab_c"]);
+        assert_eq!(find_all(r"[^\d,]+", "ab1,cd"), vec!["ab", "cd"]);
+        assert_eq!(find_all(r"[-a]", "b-a"), vec!["-", "a"]);
+        assert_eq!(find_all(r"\D\W", "1a-"), vec!["a-"]);
+        assert_eq!(find_all(r"a.c", "abc a\nc"), vec!["abc"]);
+        assert_eq!(find_all(r"\(\)", "f()"), vec!["()"]);
+        assert_eq!(find_all("é+", "aéé"), vec!["éé"]);
+    }
+
+    #[test]
+    fn test_alternation_is_leftmost_first() {
+        assert_eq!(find_all("a|ab", "ab"), vec!["a"]);
+        assert_eq!(find_all("ab|a", "ab"), vec!["ab"]);
+        assert_eq!(find_all("b|abc", "abc"), vec!["abc"]);
+        assert_eq!(find_all("do|don't", "don't do"), vec!["do", "do"]);
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert_eq!(find_all(r"\d{1,3}", "12345"), vec!["123", "45"]);
+        assert_eq!(find_all(r"\d{2}", "12345"), vec!["12", "34"]);
+        assert_eq!(find_all(r"\d{2,}", "1 23 456"), vec!["23", "456"]);
+        assert_eq!(find_all(r"a*?b", "aab"), vec!["aab"]);
+        assert_eq!(find_all(r"<.+?>", "<a><b>"), vec!["<a>", "<b>"]);
+        assert_eq!(find_all(r"<.+>", "<a><b>"), vec!["<a><b>"]);
+        assert_eq!(find_all(r"ab?c", "ac abc"), vec!["ac", "abc"]);
+        assert_eq!(find_all(r"(?:ab)+", "ababa"), vec!["abab"]);
+        assert_eq!(find_all(r"x*", "ab"), vec!["", "", ""]);
+        assert_eq!(find_all(r"(a*)*b", "aab"), vec!["aab"]);
+    }
+
+    #[test]
+    fn test_captures() {
+        let regex = Regex::new(r"mul\((\d{1,3}),(\d{1,3})\)|(do)\(\)").unwrap();
+        assert_eq!(regex.groups(), 4);
+        let text = b"xmul(2,4)do()mul(1234,5)";
+        let all = regex.find_iter(text).collect::<Vec<_>>();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].span(), 1..9);
+        assert_eq!(all[0].get(1), Some(5..6));
+        assert_eq!(all[0].get(2), Some(7..8));
+        assert_eq!(all[0].get(3), None);
+        assert_eq!(all[1].get(1), None);
+        assert_eq!(all[1].get(3), Some(9..11));
+
+        // The last iteration of a repeated group wins.
+        let regex = Regex::new(r"(\w)+").unwrap();
+        let captures = regex.find_at(b"abc", 0).unwrap();
+        assert_eq!(captures.get(1), Some(2..3));
+    }
+
+    #[test]
+    fn test_escape() {
+        let name = "f(x).y";
+        let regex = Regex::new(&escape(name)).unwrap();
+        assert_eq!(regex.find_at(b"af(x).y", 0).unwrap().span(), 1..7);
+        assert!(regex.find_at(b"f(x)zy", 0).is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("a(b", Error::UnexpectedEnd),
+            ("a)", Error::UnexpectedChar { pos: 1, ch: ')' }),
+            ("*a", Error::UnexpectedChar { pos: 0, ch: '*' }),
+            ("a**", Error::InvalidRepeat { pos: 2 }),
+            ("a{3,2}", Error::InvalidRepeat { pos: 1 }),
+            ("a{2", Error::InvalidRepeat { pos: 1 }),
+            ("a{1001}", Error::InvalidRepeat { pos: 1 }),
+            ("[z-a]", Error::InvalidClass { pos: 1 }),
+            ("[é]", Error::InvalidClass { pos: 1 }),
+            ("[ab", Error::UnexpectedEnd),
+            (r"\q", Error::UnexpectedChar { pos: 1, ch: 'q' }),
+            ("(?x)", Error::UnexpectedChar { pos: 1, ch: '?' }),
+        ];
+        for (pattern, expected) in cases {
+            assert_eq!(
+                Regex::new(pattern).unwrap_err(),
+                expected,
+                "Failed for {pattern:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! A prefix tree, shared by the days that search for many words at once.
+
+use std::collections::BTreeMap;
+
+/// A set of key sequences (e.g. `Trie<char>` for words, `Trie<u8>` for byte
+/// strings) supporting prefix queries. Lookups never consume their input.
+#[derive(Debug)]
+pub struct Trie<K> {
+    root: TrieNode<K>,
+    len: usize,
+}
+
+#[derive(Debug)]
+pub struct TrieNode<K> {
+    children: BTreeMap<K, TrieNode<K>>,
+    is_word: bool,
+}
+
+impl<K> Default for TrieNode<K> {
+    fn default() -> Self {
+        Self {
+            children: BTreeMap::new(),
+            is_word: false,
+        }
+    }
+}
+
+impl<K: Ord + Clone> Default for Trie<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone> Trie<K> {
+    pub fn new() -> Self {
+        Self {
+            root: TrieNode::default(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns false if the word was already present.
+    pub fn insert<I: IntoIterator<Item = K>>(&mut self, word: I) -> bool {
+        let mut node = &mut self.root;
+        for key in word {
+            node = node.children.entry(key).or_default();
+        }
+        if node.is_word {
+            return false;
+        }
+        node.is_word = true;
+        self.len += 1;
+        true
+    }
+
+    /// Returns false if the word wasn't present. Branches left without any
+    /// words are pruned.
+    pub fn remove<I: IntoIterator<Item = K>>(&mut self, word: I) -> bool {
+        let word = word.into_iter().collect::<Vec<_>>();
+        let removed = Self::remove_from(&mut self.root, &word);
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    fn remove_from(node: &mut TrieNode<K>, word: &[K]) -> bool {
+        let Some((key, rest)) = word.split_first() else {
+            return std::mem::replace(&mut node.is_word, false);
+        };
+        let Some(child) = node.children.get_mut(key) else {
+            return false;
+        };
+        let removed = Self::remove_from(child, rest);
+        if !child.is_word && child.children.is_empty() {
+            node.children.remove(key);
+        }
+        removed
+    }
+
+    /// The node for the empty prefix, for walking the trie a key at a time.
+    pub fn root(&self) -> &TrieNode<K> {
+        &self.root
+    }
+
+    fn find<I: IntoIterator<Item = K>>(&self, prefix: I) -> Option<&TrieNode<K>> {
+        let mut node = &self.root;
+        for key in prefix {
+            node = node.children.get(&key)?;
+        }
+        Some(node)
+    }
+
+    pub fn contains<I: IntoIterator<Item = K>>(&self, word: I) -> bool {
+        self.find(word).is_some_and(|node| node.is_word)
+    }
+
+    /// Whether any word starts with `prefix`.
+    pub fn starts_with<I: IntoIterator<Item = K>>(&self, prefix: I) -> bool {
+        !self.is_empty() && self.find(prefix).is_some()
+    }
+
+    /// Length of the longest word that is a prefix of `haystack`.
+    pub fn longest_prefix_len<I: IntoIterator<Item = K>>(&self, haystack: I) -> Option<usize> {
+        let mut node = &self.root;
+        let mut longest = node.is_word.then_some(0);
+        for (i, key) in haystack.into_iter().enumerate() {
+            node = match node.children.get(&key) {
+                Some(node) => node,
+                None => break,
+            };
+            if node.is_word {
+                longest = Some(i + 1);
+            }
+        }
+        longest
+    }
+
+    /// All words starting with `prefix`, in order.
+    pub fn words_with_prefix<I: IntoIterator<Item = K>>(
+        &self,
+        prefix: I,
+    ) -> impl Iterator<Item = Vec<K>> + '_ {
+        let prefix = prefix.into_iter().collect::<Vec<_>>();
+        let stack = match self.find(prefix.iter().cloned()) {
+            Some(node) => vec![(node, prefix)],
+            None => vec![],
+        };
+        TrieWords { stack }
+    }
+}
+
+impl<K: Ord> TrieNode<K> {
+    /// The node for this prefix followed by `key`, if any word continues that
+    /// way.
+    pub fn child(&self, key: &K) -> Option<&TrieNode<K>> {
+        self.children.get(key)
+    }
+
+    /// Whether this prefix is itself a word.
+    pub fn is_word(&self) -> bool {
+        self.is_word
+    }
+}
+
+impl Trie<char> {
+    /// The longest word that is a prefix of `text`, along with its length in
+    /// chars.
+    pub fn longest_prefix<'s>(&self, text: &'s str) -> Option<(&'s str, usize)> {
+        let len = self.longest_prefix_len(text.chars())?;
+        let end = text.char_indices().nth(len).map_or(text.len(), |(i, _)| i);
+        Some((&text[..end], len))
+    }
+}
+
+/// Depth-first traversal yielding the words below a node.
+struct TrieWords<'a, K> {
+    stack: Vec<(&'a TrieNode<K>, Vec<K>)>,
+}
+
+impl<K: Clone> Iterator for TrieWords<'_, K> {
+    type Item = Vec<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, word)) = self.stack.pop() {
+            // Pushed in reverse, so that children are visited in order.
+            for (key, child) in node.children.iter().rev() {
+                let mut child_word = word.clone();
+                child_word.push(key.clone());
+                self.stack.push((child, child_word));
+            }
+            if node.is_word {
+                return Some(word);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(trie: &Trie<char>, prefix: &str) -> Vec<String> {
+        trie.words_with_prefix(prefix.chars())
+            .map(|word| word.into_iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_trie_insert_remove() {
+        let mut trie = Trie::new();
+        assert!(trie.is_empty());
+        assert!(trie.insert("abc".chars()));
+        assert!(trie.insert("a".chars()));
+        assert!(!trie.insert("abc".chars()));
+        assert_eq!(trie.len(), 2);
+        assert!(trie.contains("abc".chars()));
+        assert!(!trie.contains("ab".chars()));
+
+        assert!(!trie.remove("ab".chars()));
+        assert!(trie.remove("abc".chars()));
+        assert!(!trie.remove("abc".chars()));
+        assert_eq!(trie.len(), 1);
+        assert!(trie.contains("a".chars()));
+        // The "bc" branch is pruned along with "abc".
+        assert!(!trie.starts_with("ab".chars()));
+    }
+
+    #[test]
+    fn test_trie_longest_prefix() {
+        let mut trie = Trie::new();
+        for word in ["a", "abc", "def", "été"] {
+            trie.insert(word.chars());
+        }
+
+        let cases = [
+            ("x", None),
+            ("a", Some(("a", 1))),
+            ("ax", Some(("a", 1))),
+            ("abx", Some(("a", 1))),
+            ("abc", Some(("abc", 3))),
+            ("abcd", Some(("abc", 3))),
+            ("de", None),
+            ("def", Some(("def", 3))),
+            ("étés", Some(("été", 3))),
+        ];
+        for (haystack, expected) in cases {
+            assert_eq!(
+                trie.longest_prefix(haystack),
+                expected,
+                "Failed for \"{}\"",
+                haystack
+            );
+        }
+    }
+
+    #[test]
+    fn test_trie_prefix_queries() {
+        let mut trie = Trie::new();
+        for word in ["do", "don't", "dot", "mul"] {
+            trie.insert(word.chars());
+        }
+        assert!(trie.starts_with("".chars()));
+        assert!(trie.starts_with("do".chars()));
+        assert!(trie.starts_with("don".chars()));
+        assert!(!trie.starts_with("dox".chars()));
+
+        assert_eq!(words(&trie, "do"), vec!["do", "don't", "dot"]);
+        assert_eq!(words(&trie, "don"), vec!["don't"]);
+        assert_eq!(words(&trie, ""), vec!["do", "don't", "dot", "mul"]);
+        assert!(words(&trie, "x").is_empty());
+
+        for word in ["do", "don't", "dot", "mul"] {
+            trie.remove(word.chars());
+        }
+        assert!(!trie.starts_with("".chars()));
+        assert!(!Trie::<char>::new().starts_with("".chars()));
+    }
+
+    #[test]
+    fn test_trie_bytes() {
+        let mut trie = Trie::new();
+        trie.insert(b"mul(".iter().copied());
+        trie.insert(b"do()".iter().copied());
+        assert_eq!(
+            trie.longest_prefix_len(b"mul(2,3)".iter().copied()),
+            Some(4)
+        );
+        assert_eq!(trie.longest_prefix_len(b"mu".iter().copied()), None);
+        assert_eq!(
+            trie.words_with_prefix([]).collect::<Vec<_>>(),
+            vec![b"do()".to_vec(), b"mul(".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_trie_walk() {
+        let mut trie = Trie::new();
+        trie.insert("ab".chars());
+        trie.insert("abc".chars());
+        let root = trie.root();
+        assert!(!root.is_word());
+        let a = root.child(&'a').unwrap();
+        assert!(!a.is_word());
+        assert!(a.child(&'c').is_none());
+        let b = a.child(&'b').unwrap();
+        assert!(b.is_word());
+        assert!(b.child(&'c').unwrap().is_word());
+    }
+}